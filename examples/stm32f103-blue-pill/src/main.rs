@@ -32,27 +32,37 @@ use core::cell::RefCell;
 use cortex_m::interrupt::Mutex;
 use cortex_m_rt::entry;
 
-use dcc_rs::{packets::*, DccInterruptHandler};
+use dcc_rs::{
+    channel::{Channel, Consumer},
+    config::Config,
+    output::SinglePin,
+    packets::*,
+    queue::TxOptions,
+    DccInterruptHandler,
+};
 
 // A type definition for the GPIO pin to be used for our LED
 type DccDirPin = gpioa::PA0<Output<PushPull>>;
 
 // Make DCC thingy globally available
-static G_DCC: Mutex<RefCell<Option<DccInterruptHandler<DccDirPin>>>> =
-    Mutex::new(RefCell::new(None));
+static G_DCC: Mutex<
+    RefCell<Option<DccInterruptHandler<SinglePin<DccDirPin>>>>,
+> = Mutex::new(RefCell::new(None));
 
 // Make timer interrupt registers globally available
 static G_TIM: Mutex<RefCell<Option<CounterUs<TIM2>>>> =
     Mutex::new(RefCell::new(None));
 
-// place for sending packets
-static TX_BUFFER: Mutex<RefCell<Option<(SerialiseBuffer, usize)>>> =
+// channel for sending packets to the interrupt handler
+static CHANNEL: Channel<4> = Channel::new();
+static G_CONSUMER: Mutex<RefCell<Option<Consumer<'static, 4>>>> =
     Mutex::new(RefCell::new(None));
 
 #[interrupt]
 fn TIM2() {
-    static mut DCC: Option<DccInterruptHandler<DccDirPin>> = None;
+    static mut DCC: Option<DccInterruptHandler<SinglePin<DccDirPin>>> = None;
     static mut TIM: Option<CounterUs<TIM2>> = None;
+    static mut CONSUMER: Option<Consumer<'static, 4>> = None;
 
     let dcc = DCC.get_or_insert_with(|| {
         cortex_m::interrupt::free(|cs| {
@@ -68,11 +78,13 @@ fn TIM2() {
         })
     });
 
-    if let Some((new_data, len)) =
-        cortex_m::interrupt::free(|cs| TX_BUFFER.borrow(cs).replace(None))
-    {
-        dcc.write(&new_data[..len]).unwrap();
-    }
+    let consumer = CONSUMER.get_or_insert_with(|| {
+        cortex_m::interrupt::free(|cs| {
+            G_CONSUMER.borrow(cs).replace(None).unwrap()
+        })
+    });
+
+    consumer.drain_into(dcc);
 
     if let Ok(new_delay) = dcc.tick() {
         tim.start(new_delay.micros()).unwrap();
@@ -109,7 +121,8 @@ fn main() -> ! {
     info!("a");
     let dcc_pin = gpioa.pa0.into_push_pull_output(&mut gpioa.crl);
 
-    let mut dcc = DccInterruptHandler::new(dcc_pin);
+    let mut dcc =
+        DccInterruptHandler::new(SinglePin::new(dcc_pin), Config::default());
     let pkt = SpeedAndDirection::builder()
         .address(10)
         .unwrap()
@@ -118,13 +131,18 @@ fn main() -> ! {
         .direction(Direction::Forward)
         .build();
     info!("a");
-    let mut buffer = SerialiseBuffer::default();
-    let len = pkt.serialise(&mut buffer).unwrap();
-    dcc.write(buffer.get(0..len).unwrap()).unwrap();
+    dcc.write_packet(&pkt).unwrap();
     info!("a");
 
+    // Split the channel, keeping the producer and handing the consumer to
+    // the interrupt handler
+    let (mut producer, consumer) = CHANNEL.split_static().unwrap();
+
     // Move the DCC thingy into our global storage
-    cortex_m::interrupt::free(|cs| *G_DCC.borrow(cs).borrow_mut() = Some(dcc));
+    cortex_m::interrupt::free(|cs| {
+        *G_DCC.borrow(cs).borrow_mut() = Some(dcc);
+        *G_CONSUMER.borrow(cs).borrow_mut() = Some(consumer);
+    });
     info!("a");
 
     // Set up a timer expiring after 1s
@@ -196,9 +214,8 @@ fn main() -> ! {
             .build();
         let mut buffer = SerialiseBuffer::default();
         let len = pkt.serialise(&mut buffer).unwrap();
-        cortex_m::interrupt::free(|cs| {
-            *TX_BUFFER.borrow(cs).borrow_mut() = Some((buffer, len))
-        });
+        // drop the packet if the channel is full; it is resent shortly
+        let _ = producer.push(&buffer[..len], TxOptions::default());
 
         delay.delay_ms(15u16);
     }
@@ -0,0 +1,345 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Lock-free single-producer single-consumer channel for passing
+//! serialised packets from the main loop to the interrupt handler.
+//!
+//! The `Channel` is split into a `Producer`, which is kept by application
+//! code, and a `Consumer`, which is owned by the interrupt handler. Only
+//! atomic loads and stores are used, so no critical sections are needed
+//! and the channel also works on cores without compare-and-swap.
+//!
+//! ```ignore
+//! static CHANNEL: Channel<4> = Channel::new();
+//!
+//! let (mut producer, mut consumer) = CHANNEL.split_static().unwrap();
+//!
+//! // main loop
+//! producer.push(&buffer[..len], TxOptions::default())?;
+//!
+//! // interrupt handler
+//! consumer.drain_into(&mut dcc);
+//! ```
+
+use crate::output::DccOutput;
+use crate::packets::SerialiseBuffer;
+use crate::queue::{Priority, TxOptions};
+use crate::{DccInterruptHandler, Error};
+use bitvec::prelude::*;
+use core::cell::UnsafeCell;
+#[cfg(target_has_atomic = "8")]
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+type Slot = (SerialiseBuffer, usize, TxOptions);
+
+const EMPTY_SLOT: Slot = (
+    SerialiseBuffer::ZERO,
+    0,
+    TxOptions {
+        priority: Priority::Normal,
+        repeats: 1,
+        service_mode: false,
+    },
+);
+
+/// Fixed-capacity channel holding up to `N` serialised packets
+pub struct Channel<const N: usize> {
+    slots: [UnsafeCell<Slot>; N],
+    /// Number of packets which have been read, modulo `2 * N`
+    head: AtomicUsize,
+    /// Number of packets which have been written, modulo `2 * N`
+    tail: AtomicUsize,
+    /// Whether `split_static` has handed out the two halves
+    #[cfg(target_has_atomic = "8")]
+    split: AtomicBool,
+}
+
+// Safety: the slots are only accessed through the `Producer` and
+// `Consumer`, which never access the same slot at the same time
+unsafe impl<const N: usize> Sync for Channel<N> {}
+
+impl<const N: usize> Default for Channel<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Channel<N> {
+    /// Create an empty channel. This is a `const fn` so that the channel
+    /// can be placed in a `static`.
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { UnsafeCell::new(EMPTY_SLOT) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            #[cfg(target_has_atomic = "8")]
+            split: AtomicBool::new(false),
+        }
+    }
+
+    /// Split the channel into its producer and consumer halves
+    pub fn split(&mut self) -> (Producer<'_, N>, Consumer<'_, N>) {
+        let channel = &*self;
+        (Producer { channel }, Consumer { channel })
+    }
+
+    /// Split a channel held in a plain `static` into its producer and
+    /// consumer halves. Only the first call succeeds; later calls return
+    /// `None`, so there can never be more than one producer or consumer.
+    ///
+    /// This needs an atomic swap, so is not available on cores without
+    /// compare-and-swap such as the Cortex-M0. Use `split` there instead.
+    #[cfg(target_has_atomic = "8")]
+    pub fn split_static(
+        &'static self,
+    ) -> Option<(Producer<'static, N>, Consumer<'static, N>)> {
+        if self.split.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        Some((Producer { channel: self }, Consumer { channel: self }))
+    }
+
+    /// Number of packets waiting in the channel. The counters wrap at
+    /// `2 * N` rather than at `usize::MAX`, so that they stay in step with
+    /// the slot indices whatever the value of `N`, while a full channel
+    /// can still be told apart from an empty one.
+    fn len(head: usize, tail: usize) -> usize {
+        (tail + 2 * N - head) % (2 * N)
+    }
+
+    /// Advance a head or tail counter by one packet
+    fn next(index: usize) -> usize {
+        (index + 1) % (2 * N)
+    }
+}
+
+/// Sending half of a `Channel`
+pub struct Producer<'a, const N: usize> {
+    channel: &'a Channel<N>,
+}
+
+// Safety: only the producer writes to the slots or to the tail
+unsafe impl<const N: usize> Send for Producer<'_, N> {}
+
+impl<const N: usize> Producer<'_, N> {
+    /// Send a serialised packet to be queued with the given options.
+    /// Returns `Error::TooLong` if the packet does not fit into a
+    /// `SerialiseBuffer`, or `Error::Full` if the channel has no room for
+    /// another packet.
+    pub fn push(
+        &mut self,
+        buf: &BitSlice<u8, Msb0>,
+        options: TxOptions,
+    ) -> Result<(), Error> {
+        let mut packet = SerialiseBuffer::ZERO;
+        packet
+            .get_mut(..buf.len())
+            .ok_or(Error::TooLong)?
+            .copy_from_bitslice(buf);
+
+        let tail = self.channel.tail.load(Ordering::Relaxed);
+        let head = self.channel.head.load(Ordering::Acquire);
+        if Channel::<N>::len(head, tail) >= N {
+            return Err(Error::Full);
+        }
+
+        // Safety: the consumer does not read this slot until the tail has
+        // been advanced past it
+        unsafe {
+            *self.channel.slots[tail % N].get() = (packet, buf.len(), options);
+        }
+        self.channel
+            .tail
+            .store(Channel::<N>::next(tail), Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns `true` if there is no room for another packet
+    pub fn is_full(&self) -> bool {
+        let tail = self.channel.tail.load(Ordering::Relaxed);
+        let head = self.channel.head.load(Ordering::Acquire);
+        Channel::<N>::len(head, tail) >= N
+    }
+}
+
+/// Receiving half of a `Channel`
+pub struct Consumer<'a, const N: usize> {
+    channel: &'a Channel<N>,
+}
+
+// Safety: only the consumer reads from the slots or writes to the head
+unsafe impl<const N: usize> Send for Consumer<'_, N> {}
+
+impl<const N: usize> Consumer<'_, N> {
+    /// Copy the oldest packet into `out`, returning its length in bits and
+    /// the options it was sent with, or `None` if the channel is empty
+    pub fn pop(
+        &mut self,
+        out: &mut SerialiseBuffer,
+    ) -> Option<(usize, TxOptions)> {
+        let head = self.channel.head.load(Ordering::Relaxed);
+        let tail = self.channel.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        // Safety: the producer does not write to this slot until the head
+        // has been advanced past it
+        let (packet, len, options) =
+            unsafe { *self.channel.slots[head % N].get() };
+        self.channel
+            .head
+            .store(Channel::<N>::next(head), Ordering::Release);
+
+        *out = packet;
+        Some((len, options))
+    }
+
+    /// Returns `true` if there are no packets waiting
+    pub fn is_empty(&self) -> bool {
+        let head = self.channel.head.load(Ordering::Relaxed);
+        let tail = self.channel.tail.load(Ordering::Acquire);
+        head == tail
+    }
+
+    /// Move packets from the channel into the handler's queue until either
+    /// the channel is empty or the queue is full. Packets which do not fit
+    /// are left in the channel. Returns the number of packets moved.
+    pub fn drain_into<O: DccOutput, const LOCOS: usize, const QUEUE: usize>(
+        &mut self,
+        dcc: &mut DccInterruptHandler<O, LOCOS, QUEUE>,
+    ) -> usize {
+        let mut moved = 0;
+        while !dcc.queue_is_full() {
+            let mut packet = SerialiseBuffer::ZERO;
+            let Some((len, options)) = self.pop(&mut packet) else {
+                break;
+            };
+            // the queue has room and the packet is shorter than the queue's
+            // buffers, so this cannot fail
+            if dcc.write_with(&packet[..len], options).is_ok() {
+                moved += 1;
+            }
+        }
+        moved
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn push_byte<const N: usize>(
+        producer: &mut Producer<'_, N>,
+        byte: u8,
+    ) -> Result<(), Error> {
+        producer.push([byte].view_bits(), TxOptions::default())
+    }
+
+    fn pop_byte<const N: usize>(consumer: &mut Consumer<'_, N>) -> Option<u8> {
+        let mut out = SerialiseBuffer::default();
+        let (len, _) = consumer.pop(&mut out)?;
+        assert_eq!(len, 8);
+        Some(out[..8].load_be())
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let mut channel = Channel::<2>::new();
+        let (mut producer, mut consumer) = channel.split();
+        assert!(consumer.is_empty());
+        assert_eq!(pop_byte(&mut consumer), None);
+
+        push_byte(&mut producer, 1).unwrap();
+        push_byte(&mut producer, 2).unwrap();
+        assert!(producer.is_full());
+        assert_eq!(push_byte(&mut producer, 3), Err(Error::Full));
+
+        assert_eq!(pop_byte(&mut consumer), Some(1));
+        push_byte(&mut producer, 3).unwrap();
+        assert_eq!(pop_byte(&mut consumer), Some(2));
+        assert_eq!(pop_byte(&mut consumer), Some(3));
+        assert_eq!(pop_byte(&mut consumer), None);
+
+        assert_eq!(
+            producer.push([0; 10].view_bits(), TxOptions::default()),
+            Err(Error::TooLong)
+        );
+    }
+
+    #[test]
+    fn capacity_which_is_not_a_power_of_two() {
+        let mut channel = Channel::<3>::new();
+        let (mut producer, mut consumer) = channel.split();
+        for byte in 0..20 {
+            push_byte(&mut producer, byte).unwrap();
+            push_byte(&mut producer, byte + 100).unwrap();
+            assert_eq!(pop_byte(&mut consumer), Some(byte));
+            assert_eq!(pop_byte(&mut consumer), Some(byte + 100));
+            assert!(consumer.is_empty());
+        }
+        for byte in 0..3 {
+            push_byte(&mut producer, byte).unwrap();
+        }
+        assert!(producer.is_full());
+        assert_eq!(pop_byte(&mut consumer), Some(0));
+    }
+
+    #[test]
+    fn split_static_once() {
+        static CHANNEL: Channel<2> = Channel::new();
+        let (mut producer, mut consumer) = CHANNEL.split_static().unwrap();
+        assert!(CHANNEL.split_static().is_none());
+
+        push_byte(&mut producer, 1).unwrap();
+        assert_eq!(pop_byte(&mut consumer), Some(1));
+    }
+
+    #[test]
+    fn drain_into_handler() {
+        use crate::config::Config;
+        use crate::output::SinglePin;
+        use crate::test::MockPin;
+
+        let mut dcc: DccInterruptHandler<_, 8, 2> = DccInterruptHandler::new(
+            SinglePin::new(MockPin::default()),
+            Config::default(),
+        );
+        let mut channel = Channel::<4>::new();
+        let (mut producer, mut consumer) = channel.split();
+        for byte in 1..=3 {
+            push_byte(&mut producer, byte).unwrap();
+        }
+
+        // the third packet stays in the channel until the queue has room
+        assert_eq!(consumer.drain_into(&mut dcc), 2);
+        assert!(dcc.queue_is_full());
+        assert_eq!(pop_byte(&mut consumer), Some(3));
+    }
+
+    #[test]
+    fn across_threads() {
+        let mut channel = Channel::<4>::new();
+        let (mut producer, mut consumer) = channel.split();
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                for byte in 0..=255 {
+                    while push_byte(&mut producer, byte).is_err() {
+                        std::thread::yield_now();
+                    }
+                }
+            });
+            for expected in 0..=255 {
+                loop {
+                    if let Some(byte) = pop_byte(&mut consumer) {
+                        assert_eq!(byte, expected);
+                        break;
+                    }
+                    std::thread::yield_now();
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,282 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Bit timing and preamble configuration for the `DccInterruptHandler`.
+//!
+//! The limits enforced here are the command station transmission limits
+//! from S-9.1 and the minimum preamble lengths from S-9.2 (operations
+//! mode) and S-9.2.3 (service mode). The RailCom cutout limits are from
+//! S-9.3.2.
+
+use crate::Error;
+
+/// Convenient Result wrapper
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Default duration of each half of a "1" bit, in microseconds
+pub const ONE_MICROS: u32 = 58;
+/// Default duration of each half of a "0" bit, in microseconds
+pub const ZERO_MICROS: u32 = 100;
+/// Default number of preamble bits for operations-mode packets
+pub const PREAMBLE: usize = 15;
+/// Default number of preamble bits for service-mode packets
+pub const SERVICE_MODE_PREAMBLE: usize = 20;
+
+/// Shortest and longest permitted half of a "1" bit
+const ONE_MICROS_RANGE: core::ops::RangeInclusive<u32> = 55..=61;
+/// Shortest and longest permitted half of a "0" bit. S-9.1 allows each
+/// half to be up to 9900us long, but limits the whole bit to 12000us.
+const ZERO_MICROS_RANGE: core::ops::RangeInclusive<u32> = 95..=6000;
+/// Permitted delays from the end of the packet end bit to the start and
+/// end of the RailCom cutout
+const CUTOUT_START_RANGE: core::ops::RangeInclusive<u32> = 26..=32;
+const CUTOUT_END_RANGE: core::ops::RangeInclusive<u32> = 454..=488;
+/// Longest preamble which still leaves room in the transmit buffer for
+/// the longest packet
+const MAX_PREAMBLE: usize = 32;
+
+/// Timing of the RailCom cutout, measured from the end of the packet end
+/// bit
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Cutout {
+    start_micros: u32,
+    end_micros: u32,
+}
+
+impl Default for Cutout {
+    fn default() -> Self {
+        Self {
+            start_micros: 29,
+            end_micros: 471,
+        }
+    }
+}
+
+impl Cutout {
+    /// Create a cutout which starts `start_micros` after the end bit and
+    /// ends `end_micros` after the end bit. The start must be between 26
+    /// and 32us and the end between 454 and 488us, otherwise
+    /// `Error::InvalidTiming` is returned.
+    pub fn new(start_micros: u32, end_micros: u32) -> Result<Self> {
+        if !CUTOUT_START_RANGE.contains(&start_micros)
+            || !CUTOUT_END_RANGE.contains(&end_micros)
+        {
+            return Err(Error::InvalidTiming);
+        }
+        Ok(Self {
+            start_micros,
+            end_micros,
+        })
+    }
+
+    /// Delay from the end of the packet end bit to the start of the cutout
+    pub fn start_micros(&self) -> u32 {
+        self.start_micros
+    }
+
+    /// Delay from the end of the packet end bit to the end of the cutout
+    pub fn end_micros(&self) -> u32 {
+        self.end_micros
+    }
+}
+
+/// Timing and preamble configuration for the `DccInterruptHandler`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Config {
+    one_micros: u32,
+    zero_micros: u32,
+    preamble: usize,
+    service_mode_preamble: usize,
+    cutout: Option<Cutout>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            one_micros: ONE_MICROS,
+            zero_micros: ZERO_MICROS,
+            preamble: PREAMBLE,
+            service_mode_preamble: SERVICE_MODE_PREAMBLE,
+            cutout: None,
+        }
+    }
+}
+
+impl Config {
+    /// Builder interface for `Config`. Use of the Builder pattern ensures
+    /// that only timings within the S-9.1 tolerances are produced.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// Duration of each half of a "1" bit, in microseconds
+    pub fn one_micros(&self) -> u32 {
+        self.one_micros
+    }
+
+    /// Duration of each half of a "0" bit, in microseconds
+    pub fn zero_micros(&self) -> u32 {
+        self.zero_micros
+    }
+
+    /// Number of preamble bits sent before operations-mode packets
+    pub fn preamble(&self) -> usize {
+        self.preamble
+    }
+
+    /// Number of preamble bits sent before service-mode packets
+    pub fn service_mode_preamble(&self) -> usize {
+        self.service_mode_preamble
+    }
+
+    /// Timing of the RailCom cutout, or `None` if the cutout is disabled
+    pub fn cutout(&self) -> Option<Cutout> {
+        self.cutout
+    }
+}
+
+/// Builder used to construct a `Config`
+#[derive(Default)]
+pub struct ConfigBuilder {
+    one_micros: Option<u32>,
+    zero_micros: Option<u32>,
+    preamble: Option<usize>,
+    service_mode_preamble: Option<usize>,
+    cutout: Option<Cutout>,
+}
+
+impl ConfigBuilder {
+    /// Sets the duration of each half of a "1" bit. This must be between
+    /// 55 and 61us, otherwise `Error::InvalidTiming` is returned.
+    pub fn one_micros(&mut self, micros: u32) -> Result<&mut Self> {
+        if !ONE_MICROS_RANGE.contains(&micros) {
+            return Err(Error::InvalidTiming);
+        }
+        self.one_micros = Some(micros);
+        Ok(self)
+    }
+
+    /// Sets the duration of each half of a "0" bit. This must be at least
+    /// 95us, and the whole bit may be no longer than 12000us, otherwise
+    /// `Error::InvalidTiming` is returned.
+    pub fn zero_micros(&mut self, micros: u32) -> Result<&mut Self> {
+        if !ZERO_MICROS_RANGE.contains(&micros) {
+            return Err(Error::InvalidTiming);
+        }
+        self.zero_micros = Some(micros);
+        Ok(self)
+    }
+
+    /// Sets the number of preamble bits sent before operations-mode
+    /// packets. This must be between 14 and 32, otherwise
+    /// `Error::InvalidPreamble` is returned.
+    pub fn preamble(&mut self, bits: usize) -> Result<&mut Self> {
+        if !(14..=MAX_PREAMBLE).contains(&bits) {
+            return Err(Error::InvalidPreamble);
+        }
+        self.preamble = Some(bits);
+        Ok(self)
+    }
+
+    /// Sets the number of preamble bits sent before service-mode packets.
+    /// This must be between 20 and 32, otherwise `Error::InvalidPreamble`
+    /// is returned.
+    pub fn service_mode_preamble(&mut self, bits: usize) -> Result<&mut Self> {
+        if !(20..=MAX_PREAMBLE).contains(&bits) {
+            return Err(Error::InvalidPreamble);
+        }
+        self.service_mode_preamble = Some(bits);
+        Ok(self)
+    }
+
+    /// Enables the RailCom cutout after each operations-mode packet. Use
+    /// `Cutout::default()` for a cutout in the middle of the permitted
+    /// timing window.
+    pub fn cutout(&mut self, cutout: Cutout) -> &mut Self {
+        self.cutout = Some(cutout);
+        self
+    }
+
+    /// Build a `Config` using the provided values, falling back to the
+    /// defaults for any which have not been provided.
+    ///
+    /// Defaults:
+    /// * `one_micros = 58`
+    /// * `zero_micros = 100`
+    /// * `preamble = 15`
+    /// * `service_mode_preamble = 20`
+    /// * no RailCom cutout
+    pub fn build(&mut self) -> Config {
+        Config {
+            one_micros: self.one_micros.unwrap_or(ONE_MICROS),
+            zero_micros: self.zero_micros.unwrap_or(ZERO_MICROS),
+            preamble: self.preamble.unwrap_or(PREAMBLE),
+            service_mode_preamble: self
+                .service_mode_preamble
+                .unwrap_or(SERVICE_MODE_PREAMBLE),
+            cutout: self.cutout,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_config() {
+        assert_eq!(Config::builder().build(), Config::default());
+        let config = Config::default();
+        assert_eq!(config.one_micros(), 58);
+        assert_eq!(config.zero_micros(), 100);
+        assert_eq!(config.preamble(), 15);
+        assert_eq!(config.service_mode_preamble(), 20);
+        assert_eq!(config.cutout(), None);
+    }
+
+    #[test]
+    fn cutout_limits() {
+        assert_eq!(Cutout::new(25, 464), Err(Error::InvalidTiming));
+        assert_eq!(Cutout::new(33, 464), Err(Error::InvalidTiming));
+        assert_eq!(Cutout::new(30, 453), Err(Error::InvalidTiming));
+        assert_eq!(Cutout::new(30, 489), Err(Error::InvalidTiming));
+
+        let cutout = Cutout::new(26, 488).unwrap();
+        assert_eq!(cutout.start_micros(), 26);
+        assert_eq!(cutout.end_micros(), 488);
+        let config = Config::builder().cutout(cutout).build();
+        assert_eq!(config.cutout(), Some(cutout));
+    }
+
+    #[test]
+    fn timing_limits() {
+        let mut builder = Config::builder();
+        assert_eq!(builder.one_micros(54).err(), Some(Error::InvalidTiming));
+        assert_eq!(builder.one_micros(62).err(), Some(Error::InvalidTiming));
+        assert_eq!(builder.zero_micros(94).err(), Some(Error::InvalidTiming));
+        assert_eq!(builder.zero_micros(6001).err(), Some(Error::InvalidTiming));
+        assert_eq!(builder.preamble(13).err(), Some(Error::InvalidPreamble));
+        assert_eq!(
+            builder.service_mode_preamble(19).err(),
+            Some(Error::InvalidPreamble)
+        );
+
+        let config = builder
+            .one_micros(55)
+            .unwrap()
+            .zero_micros(6000)
+            .unwrap()
+            .preamble(14)
+            .unwrap()
+            .service_mode_preamble(32)
+            .unwrap()
+            .build();
+        assert_eq!(config.one_micros(), 55);
+        assert_eq!(config.zero_micros(), 6000);
+        assert_eq!(config.preamble(), 14);
+        assert_eq!(config.service_mode_preamble(), 32);
+    }
+}
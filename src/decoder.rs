@@ -0,0 +1,611 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! State machine for the receiving end of the protocol: a multi-function
+//! (mobile) decoder as fitted to a loco.
+//!
+//! The `MobileDecoder` is fed the packets decoded by `Packet::parse` and
+//! keeps track of the speed, direction, function and emergency stop state
+//! which the application should drive the motor and outputs with.
+//!
+//! ```ignore
+//! if let Some(raw) = framer.push_bit(bit) {
+//!     if let Ok(packet) = Packet::parse(raw.bytes()) {
+//!         decoder.process(&packet);
+//!     }
+//! }
+//! ```
+
+use crate::packets::{
+    Address, ConsistDirection, DecoderControlInstruction, Direction, Functions,
+    Packet, Result, SpeedAndDirection,
+};
+use crate::Error;
+
+/// Multi-function decoder which follows the packets sent to its short,
+/// long or consist address.
+///
+/// The decoder's configuration is held in the same form as the relevant
+/// configuration variables: the short address (CV1), long address (CV17
+/// and CV18), consist address (CV19) and configuration (CV29).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct MobileDecoder {
+    short_address: u8,
+    long_address: u16,
+    consist_address: u8,
+    consist_direction: ConsistDirection,
+    cv29: u8,
+    speed: u8,
+    speed_steps: u8,
+    direction: Direction,
+    functions: Functions,
+    e_stop: bool,
+    analogue: bool,
+    previous: Option<Packet>,
+}
+
+impl MobileDecoder {
+    /// CV29 bit 0: reverse the direction of travel in digital mode
+    pub const CV29_REVERSE_DIRECTION: u8 = 0b0000_0001;
+    /// CV29 bit 1: use 28 speed steps rather than 14 for `SpeedAndDirection`
+    /// packets
+    pub const CV29_28_SPEED_STEPS: u8 = 0b0000_0010;
+    /// CV29 bit 2: allow running on analogue (DC) power
+    pub const CV29_ANALOGUE_CONVERSION: u8 = 0b0000_0100;
+    /// CV29 bit 5: respond to the long address rather than the short one
+    pub const CV29_LONG_ADDRESS: u8 = 0b0010_0000;
+    /// Default value of CV29: 28 speed steps with analogue conversion
+    pub const CV29_DEFAULT: u8 =
+        Self::CV29_28_SPEED_STEPS | Self::CV29_ANALOGUE_CONVERSION;
+
+    /// Create a decoder which responds to `address`, with the default CV29
+    /// settings. A short-mode decoder has no long address until one is set,
+    /// and a long-mode decoder has the default short address of 3. Returns
+    /// `Error::InvalidAddress` if the address is out of range.
+    pub fn new(address: Address) -> Result<Self> {
+        if !address.is_valid() {
+            return Err(Error::InvalidAddress);
+        }
+        let (short_address, long_address, cv29) = match address {
+            Address::Short(address) => (address, 0, Self::CV29_DEFAULT),
+            Address::Long(address) => {
+                (3, address, Self::CV29_DEFAULT | Self::CV29_LONG_ADDRESS)
+            }
+        };
+        Ok(Self {
+            short_address,
+            long_address,
+            consist_address: 0,
+            consist_direction: ConsistDirection::Normal,
+            cv29,
+            speed: 0,
+            speed_steps: 28,
+            direction: Direction::Forward,
+            functions: Functions::new(),
+            e_stop: false,
+            analogue: false,
+            previous: None,
+        })
+    }
+
+    /// Set the short address (CV1), between 1 and 127. Returns
+    /// `Error::InvalidAddress` if the address is out of range.
+    pub fn set_short_address(&mut self, address: u8) -> Result<&mut Self> {
+        Address::short(address)?;
+        self.short_address = address;
+        Ok(self)
+    }
+
+    /// Set the long address (CV17 and CV18), between 0 and 10239. This is
+    /// only used if `CV29_LONG_ADDRESS` is set. Returns
+    /// `Error::InvalidAddress` if the address is out of range.
+    pub fn set_long_address(&mut self, address: u16) -> Result<&mut Self> {
+        Address::long(address)?;
+        self.long_address = address;
+        Ok(self)
+    }
+
+    /// Set the consist address (CV19), between 1 and 127, and the direction
+    /// of the loco within the consist. An address of 0 removes the loco
+    /// from its consist. Returns `Error::InvalidAddress` if the address is
+    /// out of range.
+    pub fn set_consist(
+        &mut self,
+        address: u8,
+        direction: ConsistDirection,
+    ) -> Result<&mut Self> {
+        if address > 0x7f {
+            return Err(Error::InvalidAddress);
+        }
+        self.consist_address = address;
+        self.consist_direction = direction;
+        Ok(self)
+    }
+
+    /// Set the configuration (CV29)
+    pub fn set_cv29(&mut self, cv29: u8) -> &mut Self {
+        self.cv29 = cv29;
+        self
+    }
+
+    /// The configuration (CV29)
+    pub fn cv29(&self) -> u8 {
+        self.cv29
+    }
+
+    /// The address which the decoder currently responds to, as selected
+    /// by `CV29_LONG_ADDRESS`
+    pub fn address(&self) -> Address {
+        if self.cv29 & Self::CV29_LONG_ADDRESS != 0 {
+            Address::Long(self.long_address)
+        } else {
+            Address::Short(self.short_address)
+        }
+    }
+
+    /// The consist address and the direction of the loco within the
+    /// consist, or `None` if the loco is not part of a consist
+    pub fn consist(&self) -> Option<(Address, ConsistDirection)> {
+        (self.consist_address != 0).then_some((
+            Address::Short(self.consist_address),
+            self.consist_direction,
+        ))
+    }
+
+    /// The speed step, out of `speed_steps`. This is 0 while stopped or
+    /// after an emergency stop.
+    pub fn speed(&self) -> u8 {
+        self.speed
+    }
+
+    /// The number of speed steps used by the last speed packet: 14, 28 or
+    /// 126
+    pub fn speed_steps(&self) -> u8 {
+        self.speed_steps
+    }
+
+    /// The direction of travel, after any reversal by CV29 or the consist
+    /// direction
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// The function states
+    pub fn functions(&self) -> Functions {
+        self.functions
+    }
+
+    /// Returns `true` if the loco has been brought to an emergency stop.
+    /// This is cleared by the next speed packet which is not an emergency
+    /// stop.
+    pub fn is_e_stop(&self) -> bool {
+        self.e_stop
+    }
+
+    /// Returns `true` if the decoder is running on analogue power
+    pub fn is_analogue(&self) -> bool {
+        self.analogue
+    }
+
+    /// Return to the power-up state: stopped, facing forward and with every
+    /// function switched off. The configuration is kept.
+    pub fn reset(&mut self) {
+        self.speed = 0;
+        self.direction = Direction::Forward;
+        self.functions = Functions::new();
+        self.e_stop = false;
+        self.analogue = false;
+    }
+
+    /// Tell the decoder that there is no DCC signal but the track carries
+    /// analogue power with the given polarity. If `CV29_ANALOGUE_CONVERSION`
+    /// is set the decoder switches to analogue mode, in which the motor
+    /// speed follows the track voltage, and returns `true`. Otherwise the
+    /// loco is stopped. The decoder leaves analogue mode on receiving the
+    /// next packet.
+    pub fn analogue_detected(&mut self, direction: Direction) -> bool {
+        if self.cv29 & Self::CV29_ANALOGUE_CONVERSION == 0 {
+            self.speed = 0;
+            return false;
+        }
+        self.analogue = true;
+        self.direction = direction;
+        self.e_stop = false;
+        true
+    }
+
+    /// Apply a received packet. Returns `true` if the packet was addressed
+    /// to this decoder, either directly, through its consist address or by
+    /// broadcast.
+    ///
+    /// While the loco is part of a consist, speed and direction packets
+    /// sent to its own address are ignored in favour of those sent to the
+    /// consist address. Functions are still only controlled through the
+    /// loco's own address: function packets sent to the consist address
+    /// are ignored, as they are by a decoder whose CV21 and CV22 are left
+    /// at their default of 0.
+    ///
+    /// Instructions which change the configuration are only acted on when
+    /// received twice with no other packet to this decoder in between, as
+    /// required by S-9.2.1. Packets to other decoders do not count. The
+    /// application is responsible for programming on the main.
+    pub fn process(&mut self, packet: &Packet) -> bool {
+        self.analogue = false;
+        let repeated = self.previous == Some(*packet);
+
+        let address = self.address();
+        let consist = self.consist().map(|(address, _)| address);
+        match packet {
+            Packet::Reset(_) => self.reset(),
+            Packet::BroadcastStop(stop) => {
+                self.speed = 0;
+                self.e_stop = !stop.is_float();
+            }
+            Packet::SpeedAndDirection(pkt)
+                if Some(pkt.address()) == consist =>
+            {
+                self.set_speed_28_or_14(pkt, true);
+            }
+            Packet::SpeedAndDirection(pkt) if pkt.address() == address => {
+                if consist.is_none() {
+                    self.set_speed_28_or_14(pkt, false);
+                }
+            }
+            Packet::SpeedAndDirection128(pkt)
+                if Some(pkt.address()) == consist =>
+            {
+                self.set_speed(pkt.speed(), 126, pkt.direction(), true);
+                self.e_stop = pkt.is_e_stop();
+            }
+            Packet::SpeedAndDirection128(pkt) if pkt.address() == address => {
+                if consist.is_none() {
+                    self.set_speed(pkt.speed(), 126, pkt.direction(), false);
+                    self.e_stop = pkt.is_e_stop();
+                }
+            }
+            Packet::FunctionControl(pkt) if pkt.address() == address => {
+                self.functions.update(pkt.group(), &pkt.functions());
+            }
+            Packet::ConsistControl(pkt) if pkt.address() == address => {
+                if repeated {
+                    self.consist_address = pkt.consist_address();
+                    self.consist_direction = pkt.direction();
+                }
+            }
+            Packet::DecoderControl(pkt) if pkt.address() == address => {
+                self.decoder_control(pkt.instruction(), repeated);
+            }
+            Packet::ProgrammingOnMain(pkt) if pkt.address() == address => {}
+            _ => return false,
+        }
+        self.previous = Some(*packet);
+        true
+    }
+
+    /// Apply a `SpeedAndDirection` packet, which uses 14 or 28 speed steps
+    /// depending on CV29
+    fn set_speed_28_or_14(&mut self, pkt: &SpeedAndDirection, consist: bool) {
+        if self.cv29 & Self::CV29_28_SPEED_STEPS != 0 {
+            self.set_speed(pkt.speed(), 28, pkt.direction(), consist);
+        } else {
+            self.set_speed(pkt.speed_14(), 14, pkt.direction(), consist);
+            // the headlight state cannot be out of range
+            self.functions.set(0, pkt.headlight()).ok();
+        }
+        self.e_stop = pkt.is_e_stop();
+    }
+
+    /// Set the speed and direction, reversing the direction if required by
+    /// CV29 or by the consist direction
+    fn set_speed(
+        &mut self,
+        speed: u8,
+        steps: u8,
+        mut direction: Direction,
+        consist: bool,
+    ) {
+        if self.cv29 & Self::CV29_REVERSE_DIRECTION != 0 {
+            direction.toggle();
+        }
+        if consist && self.consist_direction == ConsistDirection::Reversed {
+            direction.toggle();
+        }
+        self.speed = speed;
+        self.speed_steps = steps;
+        self.direction = direction;
+    }
+
+    /// Apply a `DecoderControl` instruction
+    fn decoder_control(
+        &mut self,
+        instruction: DecoderControlInstruction,
+        repeated: bool,
+    ) {
+        match instruction {
+            DecoderControlInstruction::Reset => self.reset(),
+            DecoderControlInstruction::HardReset if repeated => {
+                self.reset();
+                self.consist_address = 0;
+                self.consist_direction = ConsistDirection::Normal;
+                self.cv29 = Self::CV29_DEFAULT;
+            }
+            DecoderControlInstruction::SetAdvancedAddressing(long)
+                if repeated =>
+            {
+                if long {
+                    self.cv29 |= Self::CV29_LONG_ADDRESS;
+                } else {
+                    self.cv29 &= !Self::CV29_LONG_ADDRESS;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packets::test::receive;
+    use crate::packets::*;
+
+    fn speed(address: u8, speed: u8, direction: Direction) -> Packet {
+        receive(
+            &SpeedAndDirection::builder()
+                .address(address)
+                .unwrap()
+                .speed(speed)
+                .unwrap()
+                .direction(direction)
+                .build(),
+        )
+    }
+
+    #[test]
+    fn validate_address() {
+        assert_eq!(
+            MobileDecoder::new(Address::Short(0)),
+            Err(Error::InvalidAddress)
+        );
+        assert_eq!(
+            MobileDecoder::new(Address::Long(60000)),
+            Err(Error::InvalidAddress)
+        );
+    }
+
+    #[test]
+    fn filter_by_address() {
+        let mut decoder = MobileDecoder::new(Address::Short(3)).unwrap();
+        assert!(!decoder.process(&speed(4, 10, Direction::Backward)));
+        assert_eq!(decoder.speed(), 0);
+        assert!(!decoder.process(&receive(&Idle)));
+
+        assert!(decoder.process(&speed(3, 10, Direction::Backward)));
+        assert_eq!(decoder.speed(), 10);
+        assert_eq!(decoder.speed_steps(), 28);
+        assert_eq!(decoder.direction(), Direction::Backward);
+
+        // the long address is only used once selected in CV29
+        decoder.set_long_address(1234).unwrap();
+        let pkt = receive(
+            &SpeedAndDirection128::builder()
+                .long_address(1234)
+                .unwrap()
+                .speed(100)
+                .unwrap()
+                .build(),
+        );
+        assert!(!decoder.process(&pkt));
+        decoder.set_cv29(
+            MobileDecoder::CV29_DEFAULT | MobileDecoder::CV29_LONG_ADDRESS,
+        );
+        assert_eq!(decoder.address(), Address::Long(1234));
+        assert!(decoder.process(&pkt));
+        assert_eq!(decoder.speed(), 100);
+        assert_eq!(decoder.speed_steps(), 126);
+        assert_eq!(decoder.direction(), Direction::Forward);
+    }
+
+    #[test]
+    fn speed_step_modes_and_reversal() {
+        let mut decoder = MobileDecoder::new(Address::Short(3)).unwrap();
+        decoder.set_cv29(MobileDecoder::CV29_REVERSE_DIRECTION);
+
+        // in 14 step mode the C bit is the headlight, so speed 14 of 28
+        // reads as step 7 with the headlight on
+        decoder.process(&speed(3, 14, Direction::Forward));
+        assert_eq!(decoder.speed_steps(), 14);
+        assert_eq!(decoder.speed(), 7);
+        assert!(decoder.functions().get(0));
+        assert_eq!(decoder.direction(), Direction::Backward);
+
+        let e_stop = SpeedAndDirection::builder()
+            .address(3)
+            .unwrap()
+            .speed(10)
+            .unwrap()
+            .e_stop(true)
+            .build();
+        decoder.process(&receive(&e_stop));
+        assert!(decoder.is_e_stop());
+        assert_eq!(decoder.speed(), 0);
+
+        decoder.process(&speed(3, 1, Direction::Forward));
+        assert!(!decoder.is_e_stop());
+        assert_eq!(decoder.speed(), 1);
+        assert!(!decoder.functions().get(0));
+    }
+
+    #[test]
+    fn functions() {
+        let mut decoder = MobileDecoder::new(Address::Long(1234)).unwrap();
+        let mut functions = Functions::new();
+        functions.set(1, true).unwrap().set(20, true).unwrap();
+        for group in [FunctionGroup::F0ToF4, FunctionGroup::F13ToF20] {
+            let pkt = FunctionControl::builder()
+                .long_address(1234)
+                .unwrap()
+                .group(group)
+                .functions(functions)
+                .build()
+                .unwrap();
+            assert!(decoder.process(&receive(&pkt)));
+        }
+        assert_eq!(decoder.functions(), functions);
+
+        // other groups are left unchanged
+        let pkt = FunctionControl::builder()
+            .long_address(1234)
+            .unwrap()
+            .group(FunctionGroup::F5ToF8)
+            .build()
+            .unwrap();
+        decoder.process(&receive(&pkt));
+        assert_eq!(decoder.functions(), functions);
+    }
+
+    #[test]
+    fn consist() {
+        let mut decoder = MobileDecoder::new(Address::Short(3)).unwrap();
+        let set = ConsistControl::set(
+            Address::Short(3),
+            10,
+            ConsistDirection::Reversed,
+        )
+        .unwrap();
+
+        // the consist address is only set once the packet is repeated
+        decoder.process(&receive(&set));
+        assert_eq!(decoder.consist(), None);
+        decoder.process(&receive(&set));
+        assert_eq!(
+            decoder.consist(),
+            Some((Address::Short(10), ConsistDirection::Reversed))
+        );
+
+        // speed packets to the loco's own address are ignored
+        decoder.process(&speed(3, 5, Direction::Forward));
+        assert_eq!(decoder.speed(), 0);
+        assert!(decoder.process(&speed(10, 5, Direction::Forward)));
+        assert_eq!(decoder.speed(), 5);
+        assert_eq!(decoder.direction(), Direction::Backward);
+
+        // functions are only controlled through the loco's own address
+        let mut functions = Functions::new();
+        functions.set(1, true).unwrap();
+        for address in [10, 3] {
+            let pkt = FunctionControl::builder()
+                .address(address)
+                .unwrap()
+                .group(FunctionGroup::F0ToF4)
+                .functions(functions)
+                .build()
+                .unwrap();
+            decoder.process(&receive(&pkt));
+            assert_eq!(decoder.functions().get(1), address == 3);
+        }
+
+        let clear = receive(&ConsistControl::clear(Address::Short(3)));
+        decoder.process(&clear);
+        decoder.process(&clear);
+        assert_eq!(decoder.consist(), None);
+        assert!(!decoder.process(&speed(10, 5, Direction::Forward)));
+    }
+
+    #[test]
+    fn reset_and_broadcast_stop() {
+        let mut decoder = MobileDecoder::new(Address::Short(3)).unwrap();
+        decoder.process(&speed(3, 20, Direction::Backward));
+
+        assert!(decoder.process(&receive(&BroadcastStop::float())));
+        assert_eq!(decoder.speed(), 0);
+        assert!(!decoder.is_e_stop());
+        assert!(decoder.process(&receive(&BroadcastStop::immediate())));
+        assert!(decoder.is_e_stop());
+
+        // a 128 speed step e-stop sent to the broadcast address
+        decoder.process(&speed(3, 20, Direction::Backward));
+        let e_stop = Packet::parse(&[0x00, 0x3f, 0x01, 0x3e]).unwrap();
+        assert!(decoder.process(&e_stop));
+        assert_eq!(decoder.speed(), 0);
+        assert!(decoder.is_e_stop());
+
+        decoder.functions.set(5, true).unwrap();
+        assert!(decoder.process(&receive(&Reset)));
+        assert!(!decoder.is_e_stop());
+        assert_eq!(decoder.direction(), Direction::Forward);
+        assert_eq!(decoder.functions(), Functions::new());
+    }
+
+    #[test]
+    fn decoder_control() {
+        let mut decoder = MobileDecoder::new(Address::Short(3)).unwrap();
+        decoder.set_long_address(1234).unwrap();
+        let long = receive(&DecoderControl::new(
+            Address::Short(3),
+            DecoderControlInstruction::SetAdvancedAddressing(true),
+        ));
+        decoder.process(&long);
+        assert_eq!(decoder.address(), Address::Short(3));
+        decoder.process(&long);
+        assert_eq!(decoder.address(), Address::Long(1234));
+
+        decoder.set_cv29(0);
+        let hard_reset = receive(&DecoderControl::new(
+            Address::Short(3),
+            DecoderControlInstruction::HardReset,
+        ));
+        decoder.process(&hard_reset);
+        decoder.process(&hard_reset);
+        assert_eq!(decoder.cv29(), MobileDecoder::CV29_DEFAULT);
+    }
+
+    #[test]
+    fn repeats_ignore_packets_to_other_decoders() {
+        let mut decoder = MobileDecoder::new(Address::Short(3)).unwrap();
+        decoder.set_long_address(1234).unwrap();
+        let long = receive(&DecoderControl::new(
+            Address::Short(3),
+            DecoderControlInstruction::SetAdvancedAddressing(true),
+        ));
+
+        // an Idle packet and a packet to another loco do not count
+        decoder.process(&long);
+        decoder.process(&receive(&Idle));
+        decoder.process(&speed(4, 10, Direction::Forward));
+        decoder.process(&long);
+        assert_eq!(decoder.address(), Address::Long(1234));
+
+        // but a packet to this decoder does, including a broadcast
+        let short = receive(&DecoderControl::new(
+            Address::Long(1234),
+            DecoderControlInstruction::SetAdvancedAddressing(false),
+        ));
+        decoder.process(&short);
+        decoder.process(&receive(&BroadcastStop::float()));
+        decoder.process(&short);
+        assert_eq!(decoder.address(), Address::Long(1234));
+        decoder.process(&short);
+        assert_eq!(decoder.address(), Address::Short(3));
+    }
+
+    #[test]
+    fn analogue_conversion() {
+        let mut decoder = MobileDecoder::new(Address::Short(3)).unwrap();
+        decoder.process(&speed(3, 20, Direction::Forward));
+        assert!(decoder.analogue_detected(Direction::Backward));
+        assert!(decoder.is_analogue());
+        assert_eq!(decoder.direction(), Direction::Backward);
+
+        // back to digital mode as soon as a packet arrives
+        decoder.process(&receive(&Idle));
+        assert!(!decoder.is_analogue());
+
+        decoder.set_cv29(MobileDecoder::CV29_28_SPEED_STEPS);
+        assert!(!decoder.analogue_detected(Direction::Forward));
+        assert!(!decoder.is_analogue());
+        assert_eq!(decoder.speed(), 0);
+    }
+}
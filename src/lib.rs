@@ -7,14 +7,25 @@
 
 pub use bitvec;
 use bitvec::prelude::*;
-use embedded_hal::digital::v2::OutputPin;
 
+pub mod channel;
+pub mod config;
+pub mod decoder;
+pub mod output;
 pub mod packets;
+pub mod queue;
+pub mod receiver;
+pub mod scheduler;
+pub mod waveform;
+
+use config::{Config, Cutout};
+use output::DccOutput;
+use packets::{DccPacket, Idle, SerialiseBuffer};
+use queue::{PacketQueue, TxOptions};
+use scheduler::Scheduler;
 
 const BUFFER_SIZE: usize = 24 * 8;
 type BufferType = BitArr!(for 24*8, in u8, Msb0);
-const ZERO_MICROS: u32 = 100;
-const ONE_MICROS: u32 = 58;
 
 /// Error types returned by this crate
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -29,149 +40,438 @@ pub enum Error {
     InvalidOffset,
     /// A required data field is missing
     MissingField,
+    /// Function number is outside the supported range
+    InvalidFunction,
+    /// A fixed-capacity collection has no room for another entry
+    Full,
+    /// Bit timing is outside the limits allowed by S-9.1
+    InvalidTiming,
+    /// Preamble length is outside the supported range
+    InvalidPreamble,
+    /// Error detection byte of a received packet does not match
+    InvalidChecksum,
 }
 
 #[derive(Debug)]
 enum TxState {
-    Idle {
-        second_half_of_bit: bool,
-    },
     Transmitting {
         offset: usize,
         second_half_of_bit: bool,
     },
+    CutoutStart,
+    Cutout,
+    CutoutEnd,
 }
 
 /// The main interrupt handler. Calling the `tick` method advances the
 /// internal state and toggles the provided output pin to control the
-/// track polarity
-pub struct DccInterruptHandler<P: OutputPin> {
-    write_buffer: BufferType,
-    write_buffer_len: usize,
+/// track polarity.
+///
+/// Packets staged with `write` are held in a priority queue of up to
+/// `QUEUE` packets and are sent first. Otherwise the handler sends the next
+/// refresh packet from its `Scheduler`, which holds the state of up to
+/// `LOCOS` locos, or an `Idle` packet if there is nothing to refresh.
+///
+/// The bit timings and preamble lengths are set by the `Config` passed to
+/// `new`. Each packet's preamble is lengthened or shortened to match the
+/// configured length before it is sent.
+///
+/// If the `Config` enables the RailCom cutout and the output supports it
+/// then a cutout follows the end bit of every operations-mode packet.
+pub struct DccInterruptHandler<
+    O: DccOutput,
+    const LOCOS: usize = 8,
+    const QUEUE: usize = 8,
+> {
+    queue: PacketQueue<QUEUE>,
     buffer: BufferType,
     buffer_num_bits: usize,
     state: TxState,
-    output_pin: P,
+    output: O,
+    pending: Option<(bool, u32)>,
+    cutout: Option<Cutout>,
+    cutout_after_packet: bool,
+    config: Config,
+    scheduler: Scheduler<LOCOS>,
+    deferred: BufferType,
+    deferred_num_bits: usize,
+    last_address: Option<[u8; 2]>,
+    analogue_throttle: i8,
+    stretch_micros: u32,
 }
 
-impl<P: OutputPin> DccInterruptHandler<P> {
-    /// Initialise the interrupt handler. `output_pin` is the GPIO pin
-    /// connected to e.g. a motor shield's `direction` pin to control the
-    /// track polarity.
-    pub fn new(output_pin: P) -> Self {
+impl<O: DccOutput, const LOCOS: usize, const QUEUE: usize>
+    DccInterruptHandler<O, LOCOS, QUEUE>
+{
+    /// Initialise the interrupt handler. `output` drives the track, e.g. a
+    /// `SinglePin` connected to a motor shield's `direction` pin. `config`
+    /// sets the bit timings and preamble lengths; use `Config::default()`
+    /// for the standard timings.
+    ///
+    /// No RailCom cutout is generated if the output does not support it,
+    /// even if it is enabled in `config`.
+    pub fn new(output: O, config: Config) -> Self {
+        let cutout = config.cutout().filter(|_| output.supports_cutout());
         Self {
-            write_buffer: BitArray::default(),
-            write_buffer_len: 0,
+            queue: PacketQueue::new(),
             buffer: BitArray::default(),
             buffer_num_bits: 0,
-            state: TxState::Idle {
+            state: TxState::Transmitting {
+                offset: 0,
                 second_half_of_bit: false,
             },
-            output_pin,
+            output,
+            pending: None,
+            cutout,
+            cutout_after_packet: false,
+            config,
+            scheduler: Scheduler::new(),
+            deferred: BitArray::default(),
+            deferred_num_bits: 0,
+            last_address: None,
+            analogue_throttle: 0,
+            stretch_micros: 0,
         }
     }
 
+    /// The bit timing and preamble configuration
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Set the throttle for a single analogue (non-decoder) loco, between
+    /// -100 and 100. Returns `Error::InvalidSpeed` if the throttle is
+    /// outside this range.
+    ///
+    /// A non-zero throttle stretches one half of each "0" bit, giving the
+    /// track signal a DC offset which an analogue loco will run on. A
+    /// positive throttle stretches the second (high) half of each bit and
+    /// a negative throttle stretches the first (low) half. At full throttle
+    /// each stretched half lasts as long as S-9.1 allows: no more than
+    /// 9900us, and no more than 12000us for the whole bit. Decoders are
+    /// unaffected because the bit values themselves are unchanged.
+    pub fn set_analogue_throttle(&mut self, throttle: i8) -> Result<(), Error> {
+        if !(-100..=100).contains(&throttle) {
+            return Err(Error::InvalidSpeed);
+        }
+        let zero = self.config.zero_micros();
+        let max_stretch = 9900.min(12000 - zero) - zero;
+        self.analogue_throttle = throttle;
+        self.stretch_micros =
+            max_stretch * u32::from(throttle.unsigned_abs()) / 100;
+        Ok(())
+    }
+
+    /// The current analogue throttle, between -100 and 100
+    pub fn analogue_throttle(&self) -> i8 {
+        self.analogue_throttle
+    }
+
+    /// Switch the track power on or off, e.g. to shut down after a short
+    /// circuit. Packets continue to be generated while the power is off.
+    pub fn set_power(&mut self, on: bool) -> Result<(), O::Error> {
+        self.output.set_enabled(on)
+    }
+
+    /// The table of locos whose state is continuously refreshed
+    pub fn scheduler(&self) -> &Scheduler<LOCOS> {
+        &self.scheduler
+    }
+
+    /// Mutable access to the table of locos whose state is continuously
+    /// refreshed, e.g. to update a loco's speed
+    pub fn scheduler_mut(&mut self) -> &mut Scheduler<LOCOS> {
+        &mut self.scheduler
+    }
+
     /// Run on interrupt; returns the new clock count to set the interrupt to
     #[inline(always)]
-    pub fn tick(&mut self) -> Result<u32, P::Error> {
+    pub fn tick(&mut self) -> Result<u32, O::Error> {
         #[cfg(test)]
         {
             eprintln!("[tick] DCC state:");
-            eprintln!(
-                "  write_buffer: (len {}) {:?}",
-                self.write_buffer_len,
-                &self.write_buffer[..self.write_buffer_len]
-            );
+            eprintln!("  queued packets: {}", self.queue.len());
             eprintln!("  state {:?}", self.state,);
         }
 
+        // finish a change of polarity once the dead time has elapsed
+        if let Some((polarity, remaining)) = self.pending.take() {
+            self.output.set_polarity(polarity)?;
+            return Ok(remaining);
+        }
+
         let new_clock;
-        self.state = match self.state {
-            TxState::Idle { second_half_of_bit } => {
-                // transmit a zero
-                if second_half_of_bit {
-                    self.output_pin.set_high()?;
-                } else {
-                    self.output_pin.set_low()?;
-                }
-                new_clock = ZERO_MICROS;
-
-                if second_half_of_bit && self.write_buffer_len != 0 {
-                    // copy write buffer into internal buffer
-                    self.buffer.copy_from_bitslice(&self.write_buffer);
-                    self.buffer_num_bits = self.write_buffer_len;
-                    self.write_buffer_len = 0;
-                    #[cfg(test)]
-                    eprintln!("Loaded new data into tx buffer");
-
-                    TxState::Transmitting {
-                        offset: 0,
-                        second_half_of_bit: false,
-                    }
-                } else {
-                    TxState::Idle {
-                        second_half_of_bit: !second_half_of_bit,
-                    }
-                }
-            }
+        (self.state, new_clock) = match self.state {
             TxState::Transmitting {
-                mut offset,
+                offset,
                 second_half_of_bit,
-            } => {
-                // transmit the next bit-half in the sequence
-                let current_bit = *self.buffer.get(offset).unwrap();
-
-                new_clock = if current_bit { ONE_MICROS } else { ZERO_MICROS };
-
-                if second_half_of_bit {
-                    self.output_pin.set_high()?;
-                    // increment offset
-                    offset += 1;
-                } else {
-                    self.output_pin.set_low()?;
-                }
-
-                // if there is remaining data then continue transmitting,
-                // otherwise go back to Idle mode
-                if offset < self.buffer_num_bits {
-                    TxState::Transmitting {
-                        offset,
-                        second_half_of_bit: !second_half_of_bit,
-                    }
-                } else {
-                    TxState::Idle {
-                        second_half_of_bit: false,
-                    }
-                }
+            } => self.transmit(offset, second_half_of_bit)?,
+            TxState::CutoutStart => {
+                // the end bit finishes as normal, and the track is driven
+                // with the opposite polarity for a short time before the
+                // cutout begins
+                let cutout = self.cutout.unwrap_or_default();
+                (TxState::Cutout, self.drive(false, cutout.start_micros())?)
+            }
+            TxState::Cutout => {
+                self.output.set_cutout(true)?;
+                let cutout = self.cutout.unwrap_or_default();
+                (
+                    TxState::CutoutEnd,
+                    cutout.end_micros() - cutout.start_micros(),
+                )
+            }
+            TxState::CutoutEnd => {
+                self.output.set_cutout(false)?;
+                self.transmit(0, false)?
             }
         };
 
         Ok(new_clock)
     }
 
-    /// Stage a packet for transmission
-    pub fn write(&mut self, buf: &BitSlice<u8, Msb0>) -> Result<(), Error> {
-        if buf.len() > BUFFER_SIZE {
-            Err(Error::TooLong)
+    /// Transmit the next bit-half of the packet, returning the next state
+    /// and the duration of the bit-half
+    #[inline(always)]
+    fn transmit(
+        &mut self,
+        mut offset: usize,
+        second_half_of_bit: bool,
+    ) -> Result<(TxState, u32), O::Error> {
+        // load the next packet as soon as the previous one has finished, so
+        // that its preamble immediately follows the packet end bit
+        if offset == 0 && !second_half_of_bit {
+            self.load_next_packet();
+        }
+
+        // transmit the next bit-half in the sequence
+        let current_bit = *self.buffer.get(offset).unwrap();
+
+        let new_clock = if current_bit {
+            self.config.one_micros()
+        } else if second_half_of_bit == (self.analogue_throttle > 0) {
+            // stretch one half of the bit to run an analogue loco
+            self.config.zero_micros() + self.stretch_micros
+        } else {
+            self.config.zero_micros()
+        };
+
+        let new_clock = self.drive(second_half_of_bit, new_clock)?;
+        if second_half_of_bit {
+            // increment offset
+            offset += 1;
+        }
+
+        // start again from the beginning of the next packet once this one
+        // has been sent, with a RailCom cutout in between if enabled
+        if offset >= self.buffer_num_bits {
+            if self.cutout_after_packet {
+                return Ok((TxState::CutoutStart, new_clock));
+            }
+            offset = 0;
+        }
+
+        Ok((
+            TxState::Transmitting {
+                offset,
+                second_half_of_bit: !second_half_of_bit,
+            },
+            new_clock,
+        ))
+    }
+
+    /// Set the track polarity for a bit-half lasting `micros`, returning the
+    /// delay until the next tick. If the output needs a dead time then it
+    /// is released first and the new polarity is set on the next tick.
+    #[inline(always)]
+    fn drive(&mut self, polarity: bool, micros: u32) -> Result<u32, O::Error> {
+        let dead_time = self.output.dead_time_micros();
+        if dead_time == 0 {
+            self.output.set_polarity(polarity)?;
+            Ok(micros)
         } else {
-            self.write_buffer[0..buf.len()].copy_from_bitslice(buf);
-            self.write_buffer_len = buf.len();
+            self.output.release()?;
+            self.pending = Some((polarity, micros - dead_time));
+            Ok(dead_time)
+        }
+    }
+
+    /// Load the next packet into the internal buffer: a deferred or queued
+    /// packet if there is one, otherwise a refresh packet or an `Idle`
+    /// packet.
+    ///
+    /// S-9.2 requires a gap of at least 5 ms between packets sent to the
+    /// same decoder, so if the next packet has the same address as the
+    /// previous one it is deferred and an `Idle` packet is sent in between.
+    /// With the shortest bits and preamble which `Config` allows, the
+    /// `Idle` packet's 31 one bits take 3.41 ms and its 11 zero bits take
+    /// 2.09 ms, so the gap is always above 5 ms. Only consecutive packets
+    /// are compared. Service-mode packets are exempt, as S-9.2.3 requires them
+    /// to be repeated back-to-back. Refresh packets are only sent when the
+    /// queue is empty, so they never interrupt a queued service-mode
+    /// sequence.
+    fn load_next_packet(&mut self) {
+        let mut service_mode = false;
+        if self.deferred_num_bits != 0 {
+            let len = self.deferred_num_bits;
+            self.buffer[..len].copy_from_bitslice(&self.deferred[..len]);
+            self.buffer_num_bits = len;
+            self.deferred_num_bits = 0;
+        } else if let Some((len, options)) = self.queue.pop(&mut self.buffer) {
+            self.buffer_num_bits = len;
+            service_mode = options.service_mode;
             #[cfg(test)]
-            eprintln!("Written {} bits to write buffer", buf.len());
-            Ok(())
+            eprintln!("Loaded new data into tx buffer");
+        } else {
+            let mut packet = SerialiseBuffer::default();
+            let len = self
+                .scheduler
+                .next_packet(&mut packet)
+                .unwrap_or_else(|| Self::serialise_idle(&mut packet));
+            self.buffer[..len].copy_from_bitslice(&packet[..len]);
+            self.buffer_num_bits = len;
+        }
+
+        // service-mode instructions start with `0111`, which would look
+        // like a short address, so only operations-mode packets are checked
+        let address = if service_mode {
+            None
+        } else {
+            packet_address(&self.buffer[..self.buffer_num_bits])
+        };
+        if address.is_some() && address == self.last_address {
+            let len = self.buffer_num_bits;
+            self.deferred[..len].copy_from_bitslice(&self.buffer[..len]);
+            self.deferred_num_bits = len;
+
+            let mut packet = SerialiseBuffer::default();
+            let len = Self::serialise_idle(&mut packet);
+            self.buffer[..len].copy_from_bitslice(&packet[..len]);
+            self.buffer_num_bits = len;
+            self.last_address = None;
+        } else {
+            self.last_address = address;
+        }
+
+        // RailCom is not used on the programming track
+        self.cutout_after_packet = self.cutout.is_some() && !service_mode;
+
+        if service_mode {
+            self.set_preamble(self.config.service_mode_preamble());
+        } else {
+            self.set_preamble(self.config.preamble());
+        }
+    }
+
+    /// Lengthen or shorten the preamble of the packet in the internal
+    /// buffer to `bits` one bits. The packet is sent unchanged if the
+    /// longer packet would not fit into the buffer.
+    fn set_preamble(&mut self, bits: usize) {
+        let len = self.buffer_num_bits;
+        let current = self.buffer[..len].first_zero().unwrap_or(len);
+        if bits > current {
+            let extra = bits - current;
+            if len + extra <= BUFFER_SIZE {
+                self.buffer[..len + extra].shift_end(extra);
+                self.buffer[..extra].fill(true);
+                self.buffer_num_bits = len + extra;
+            }
+        } else {
+            let excess = current - bits;
+            self.buffer[..len].shift_start(excess);
+            self.buffer_num_bits = len - excess;
+        }
+    }
+
+    /// Serialise an `Idle` packet, returning its length
+    fn serialise_idle(buf: &mut SerialiseBuffer) -> usize {
+        // serialising an Idle packet cannot fail
+        Idle.serialise(buf).unwrap_or_default()
+    }
+
+    /// Queue a packet to be sent once at `Priority::Normal`. Returns
+    /// `Error::TooLong` if the packet does not fit into the internal buffer,
+    /// or `Error::Full` if the queue has no room for another packet.
+    ///
+    /// The leading one bits of `buf` are treated as its preamble, which is
+    /// lengthened or shortened to the configured length when the packet is
+    /// sent. Data which has already been serialised with a preamble is
+    /// therefore sent with a single preamble, while data without one has a
+    /// preamble added in front of it.
+    pub fn write(&mut self, buf: &BitSlice<u8, Msb0>) -> Result<(), Error> {
+        self.write_with(buf, TxOptions::default())
+    }
+
+    /// Queue a packet with the given priority and repeat count. Returns
+    /// `Error::TooLong` if the packet does not fit into the internal buffer,
+    /// or `Error::Full` if the queue has no room for another packet.
+    pub fn write_with(
+        &mut self,
+        buf: &BitSlice<u8, Msb0>,
+        options: TxOptions,
+    ) -> Result<(), Error> {
+        self.queue.push(buf, options)?;
+        #[cfg(test)]
+        eprintln!("Queued {} bits", buf.len());
+        Ok(())
+    }
+
+    /// Serialise and queue a packet at `Priority::Normal`, using its
+    /// `DccPacket::repeats` and `DccPacket::is_service_mode` hints. Returns
+    /// `Error::Full` if the queue has no room for another packet.
+    pub fn write_packet<P: DccPacket>(
+        &mut self,
+        packet: &P,
+    ) -> Result<(), Error> {
+        let mut buf = SerialiseBuffer::ZERO;
+        let len = packet.serialise(&mut buf)?;
+        let options = TxOptions {
+            repeats: packet.repeats(),
+            service_mode: packet.is_service_mode(),
+            ..TxOptions::default()
+        };
+        self.write_with(&buf[..len], options)
+    }
+
+    /// Returns `true` if the queue has no room for another packet
+    pub fn queue_is_full(&self) -> bool {
+        self.queue.is_full()
+    }
+
+    /// Drop every packet which is waiting in the queue, e.g. before sending
+    /// an emergency stop
+    pub fn clear_queue(&mut self) {
+        self.queue.clear();
+    }
+}
+
+/// Pull the address bytes out of a serialised operations-mode packet, for
+/// checking whether consecutive packets are sent to the same decoder. The
+/// second byte is only used for long and accessory addresses. Returns `None`
+/// for `Idle` packets or if there is no start bit.
+fn packet_address(packet: &BitSlice<u8, Msb0>) -> Option<[u8; 2]> {
+    let start = packet.first_zero()? + 1;
+    let first: u8 = packet.get(start..start + 8)?.load_be();
+    match first {
+        0xff => None,
+        0x80..=0xe7 => {
+            let second = packet.get(start + 9..start + 17)?.load_be();
+            Some([first, second])
         }
+        _ => Some([first, 0]),
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use config::{ONE_MICROS, ZERO_MICROS};
     use embedded_hal::digital::v2::*;
     use std::convert::Infallible;
 
     #[derive(Default)]
-    struct MockPin {
-        state: bool,
+    pub(crate) struct MockPin {
+        pub(crate) state: bool,
     }
 
     impl OutputPin for MockPin {
@@ -202,6 +502,49 @@ mod test {
         }
     }
 
+    #[derive(Default)]
+    struct MockOutput {
+        polarity: bool,
+        released: bool,
+        cutout: bool,
+        enabled: bool,
+        supports_cutout: bool,
+        dead_time_micros: u32,
+    }
+
+    impl DccOutput for MockOutput {
+        type Error = Infallible;
+
+        fn set_polarity(&mut self, polarity: bool) -> Result<(), Self::Error> {
+            self.polarity = polarity;
+            self.released = false;
+            Ok(())
+        }
+
+        fn dead_time_micros(&self) -> u32 {
+            self.dead_time_micros
+        }
+
+        fn release(&mut self) -> Result<(), Self::Error> {
+            self.released = true;
+            Ok(())
+        }
+
+        fn supports_cutout(&self) -> bool {
+            self.supports_cutout
+        }
+
+        fn set_cutout(&mut self, active: bool) -> Result<(), Self::Error> {
+            self.cutout = active;
+            Ok(())
+        }
+
+        fn set_enabled(&mut self, enabled: bool) -> Result<(), Self::Error> {
+            self.enabled = enabled;
+            Ok(())
+        }
+    }
+
     #[test]
     fn mock_pin_works() {
         let mut pin = MockPin::default();
@@ -216,16 +559,17 @@ mod test {
     fn send_a_packet() {
         const ZERO: u32 = 100;
         const ONE: u32 = 58;
-        let pin = MockPin::default();
-        let mut dcc = DccInterruptHandler::new(pin);
+        let output = MockOutput::default();
+        let mut dcc: DccInterruptHandler<_> =
+            DccInterruptHandler::new(output, Config::default());
         let buffer = [0x00, 0xff].view_bits();
         dcc.write(buffer).unwrap();
 
-        // first two ticks are idle
-        for _ in 0..2 {
+        // the configured 15 bit preamble is sent before the packet
+        for _ in 0..30 {
             let new_delay = dcc.tick().unwrap();
             eprintln!("new delay: {new_delay}");
-            assert_eq!(new_delay, ZERO);
+            assert_eq!(new_delay, ONE);
         }
 
         // run 32 ticks to make sure that the clock settings are correct
@@ -244,11 +588,427 @@ mod test {
             assert_eq!(new_delay, ONE);
         }
 
-        // after packet is finished we just have idle zeroes
-        for _ in 0..8 {
+        // after packet is finished it is immediately followed by the
+        // preamble of an Idle packet
+        for _ in 0..30 {
             let new_delay = dcc.tick().unwrap();
             eprintln!("new delay: {new_delay}");
-            assert_eq!(new_delay, ZERO);
+            assert_eq!(new_delay, ONE);
+        }
+    }
+
+    /// Run the handler through one packet, returning the bits that were
+    /// transmitted
+    fn capture_packet<const LOCOS: usize, const QUEUE: usize>(
+        dcc: &mut DccInterruptHandler<MockOutput, LOCOS, QUEUE>,
+    ) -> SerialiseBuffer {
+        let mut captured = SerialiseBuffer::default();
+        let mut offset = 0;
+        loop {
+            let first = dcc.tick().unwrap();
+            let second = dcc.tick().unwrap();
+            assert_eq!(first, second);
+            captured.set(offset, first == ONE_MICROS);
+            offset += 1;
+            if let TxState::Transmitting { offset: 0, .. } = dcc.state {
+                return captured;
+            }
+        }
+    }
+
+    #[test]
+    fn existing_preamble_is_not_doubled() {
+        let output = MockOutput::default();
+        let mut dcc: DccInterruptHandler<_> =
+            DccInterruptHandler::new(output, Config::default());
+
+        let mut buffer = SerialiseBuffer::default();
+        let len = packets::Reset.serialise(&mut buffer).unwrap();
+        dcc.write(&buffer[..len]).unwrap();
+        assert_eq!(capture_packet(&mut dcc)[..len], buffer[..len]);
+    }
+
+    #[test]
+    fn idle_and_refresh_packets() {
+        let output = MockOutput::default();
+        let mut dcc: DccInterruptHandler<_> =
+            DccInterruptHandler::new(output, Config::default());
+
+        let mut expected = SerialiseBuffer::default();
+        let len = Idle.serialise(&mut expected).unwrap();
+        assert_eq!(capture_packet(&mut dcc)[..len], expected[..len]);
+
+        let mut state = scheduler::LocoState::new(packets::Address::Short(3));
+        state.speed = 10;
+        dcc.scheduler_mut().update(state).unwrap();
+        let len = packets::SpeedAndDirection128::builder()
+            .address(3)
+            .unwrap()
+            .speed(10)
+            .unwrap()
+            .build()
+            .serialise(&mut expected)
+            .unwrap();
+        assert_eq!(capture_packet(&mut dcc)[..len], expected[..len]);
+    }
+
+    #[test]
+    fn send_a_six_byte_packet() {
+        let pkt = packets::AccessoryProgrammingOnMain::builder()
+            .board_address(1)
+            .unwrap()
+            .cv_address(513)
+            .unwrap()
+            .write_byte(0x55)
+            .build()
+            .unwrap();
+        let mut buffer = packets::SerialiseBuffer::default();
+        let len = pkt.serialise(&mut buffer).unwrap();
+        assert_eq!(len, 70);
+
+        let output = MockOutput::default();
+        let mut dcc: DccInterruptHandler<_> =
+            DccInterruptHandler::new(output, Config::default());
+        dcc.write(&buffer[..len]).unwrap();
+
+        // every bit of the packet is sent as two equal half-bits
+        for bit in buffer[..len].iter() {
+            let expected = if *bit { ONE_MICROS } else { ZERO_MICROS };
+            assert_eq!(dcc.tick().unwrap(), expected);
+            assert_eq!(dcc.tick().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn emergency_packets_jump_the_queue() {
+        let output = MockOutput::default();
+        let mut dcc: DccInterruptHandler<_, 8, 2> =
+            DccInterruptHandler::new(output, Config::default());
+
+        let mut speed = SerialiseBuffer::default();
+        let speed_len = packets::SpeedAndDirection::builder()
+            .address(3)
+            .unwrap()
+            .speed(10)
+            .unwrap()
+            .build()
+            .serialise(&mut speed)
+            .unwrap();
+        let mut stop = SerialiseBuffer::default();
+        let stop_len = packets::BroadcastStop::immediate()
+            .serialise(&mut stop)
+            .unwrap();
+
+        dcc.write(&speed[..speed_len]).unwrap();
+        dcc.write_with(
+            &stop[..stop_len],
+            TxOptions::new(queue::Priority::Emergency, 2),
+        )
+        .unwrap();
+        assert_eq!(dcc.write(&speed[..speed_len]), Err(Error::Full));
+
+        let mut idle = SerialiseBuffer::default();
+        let idle_len = Idle.serialise(&mut idle).unwrap();
+
+        // repeats of the same packet are separated by an Idle packet
+        assert_eq!(capture_packet(&mut dcc)[..stop_len], stop[..stop_len]);
+        assert_eq!(capture_packet(&mut dcc)[..idle_len], idle[..idle_len]);
+        assert_eq!(capture_packet(&mut dcc)[..stop_len], stop[..stop_len]);
+        assert_eq!(capture_packet(&mut dcc)[..speed_len], speed[..speed_len]);
+    }
+
+    #[test]
+    fn minimum_gap_between_packets_to_same_decoder() {
+        let output = MockOutput::default();
+        let mut dcc: DccInterruptHandler<_> =
+            DccInterruptHandler::new(output, Config::default());
+
+        let mut state = scheduler::LocoState::new(packets::Address::Long(1234));
+        state.speed = 10;
+        dcc.scheduler_mut().update(state).unwrap();
+
+        let mut idle = SerialiseBuffer::default();
+        let idle_len = Idle.serialise(&mut idle).unwrap();
+
+        // the only loco is refreshed with an Idle packet between each
+        // packet, and the packet after each Idle packet is the deferred one
+        let mut expected = SerialiseBuffer::default();
+        let len = packets::SpeedAndDirection128::builder()
+            .long_address(1234)
+            .unwrap()
+            .speed(10)
+            .unwrap()
+            .build()
+            .serialise(&mut expected)
+            .unwrap();
+        assert_eq!(capture_packet(&mut dcc)[..len], expected[..len]);
+        for _ in 0..3 {
+            assert_eq!(capture_packet(&mut dcc)[..idle_len], idle[..idle_len]);
+            let packet = capture_packet(&mut dcc);
+            assert_eq!(packet_address(&packet), Some([0xc4, 0xd2]));
+        }
+    }
+
+    #[test]
+    fn service_mode_packets_have_no_address() {
+        let output = MockOutput::default();
+        let mut dcc: DccInterruptHandler<_> =
+            DccInterruptHandler::new(output, Config::default());
+        let state = scheduler::LocoState::new(packets::Address::Short(0x7c));
+        dcc.scheduler_mut().update(state).unwrap();
+
+        // the instruction starts with 0x7c, the same as the loco's address
+        let packet = packets::Instruction::builder()
+            .cv_address(1)
+            .unwrap()
+            .write_byte(3)
+            .build()
+            .unwrap();
+        assert_eq!(packet.to_bytes().bytes()[0], 0x7c);
+        let mut buffer = SerialiseBuffer::default();
+        let len = packet.serialise(&mut buffer).unwrap();
+        dcc.write_with(&buffer[..len], TxOptions::service_mode(1))
+            .unwrap();
+        capture_packet(&mut dcc);
+
+        // so the refresh packet is not deferred behind an Idle packet
+        let packet = capture_packet(&mut dcc);
+        assert_eq!(packet_address(&packet), Some([0x7c, 0]));
+    }
+
+    #[test]
+    fn custom_timing_and_preamble() {
+        let output = MockOutput::default();
+        let config = Config::builder()
+            .one_micros(56)
+            .unwrap()
+            .zero_micros(120)
+            .unwrap()
+            .preamble(17)
+            .unwrap()
+            .build();
+        let mut dcc: DccInterruptHandler<_> =
+            DccInterruptHandler::new(output, config);
+
+        // Idle packet with a lengthened preamble
+        for _ in 0..17 * 2 {
+            assert_eq!(dcc.tick().unwrap(), 56);
         }
+        for _ in 0..2 {
+            assert_eq!(dcc.tick().unwrap(), 120);
+        }
+    }
+
+    #[test]
+    fn shortest_idle_packet_takes_5ms() {
+        let output = MockOutput::default();
+        let config = Config::builder()
+            .one_micros(55)
+            .unwrap()
+            .zero_micros(95)
+            .unwrap()
+            .preamble(14)
+            .unwrap()
+            .build();
+        let mut dcc: DccInterruptHandler<_> =
+            DccInterruptHandler::new(output, config);
+
+        let mut micros = 0;
+        loop {
+            micros += dcc.tick().unwrap() + dcc.tick().unwrap();
+            if let TxState::Transmitting { offset: 0, .. } = dcc.state {
+                break;
+            }
+        }
+        assert_eq!(micros, 5500);
+    }
+
+    #[test]
+    fn service_mode_packets() {
+        let output = MockOutput::default();
+        let mut dcc: DccInterruptHandler<_> =
+            DccInterruptHandler::new(output, Config::default());
+
+        let mut buffer = SerialiseBuffer::default();
+        let len = packets::Instruction::builder()
+            .cv_address(1)
+            .unwrap()
+            .write_byte(3)
+            .build()
+            .unwrap()
+            .serialise(&mut buffer)
+            .unwrap();
+        dcc.write_with(&buffer[..len], TxOptions::service_mode(2))
+            .unwrap();
+
+        // both repeats are sent back-to-back with a 20 bit preamble
+        let mut expected = SerialiseBuffer::default();
+        expected[..5].fill(true);
+        expected[5..len + 5].copy_from_bitslice(&buffer[..len]);
+        for _ in 0..2 {
+            let packet = capture_packet(&mut dcc);
+            assert_eq!(packet[..len + 5], expected[..len + 5]);
+        }
+
+        // followed by an Idle packet with the standard preamble
+        let mut idle = SerialiseBuffer::default();
+        let idle_len = Idle.serialise(&mut idle).unwrap();
+        assert_eq!(capture_packet(&mut dcc)[..idle_len], idle[..idle_len]);
+    }
+
+    #[test]
+    fn write_packet_uses_hints() {
+        let output = MockOutput::default();
+        let mut dcc: DccInterruptHandler<_> =
+            DccInterruptHandler::new(output, Config::default());
+
+        let packet = packets::Packet::PagePreset(packets::PagePreset);
+        dcc.write_packet(&packet).unwrap();
+
+        // sent five times back-to-back with a 20 bit preamble
+        let mut buffer = SerialiseBuffer::default();
+        let len = packet.serialise(&mut buffer).unwrap();
+        let mut expected = SerialiseBuffer::default();
+        expected[..5].fill(true);
+        expected[5..len + 5].copy_from_bitslice(&buffer[..len]);
+        for _ in 0..5 {
+            let captured = capture_packet(&mut dcc);
+            assert_eq!(captured[..len + 5], expected[..len + 5]);
+        }
+        let mut idle = SerialiseBuffer::default();
+        let idle_len = Idle.serialise(&mut idle).unwrap();
+        assert_eq!(capture_packet(&mut dcc)[..idle_len], idle[..idle_len]);
+    }
+
+    #[test]
+    fn analogue_throttle_stretches_zeros() {
+        let output = MockOutput::default();
+        let mut dcc: DccInterruptHandler<_> =
+            DccInterruptHandler::new(output, Config::default());
+        assert_eq!(dcc.set_analogue_throttle(101), Err(Error::InvalidSpeed));
+        assert_eq!(dcc.set_analogue_throttle(-101), Err(Error::InvalidSpeed));
+
+        // full forward throttle stretches the second half of each zero to
+        // the 9900us limit
+        dcc.set_analogue_throttle(100).unwrap();
+        assert_eq!(dcc.analogue_throttle(), 100);
+        for _ in 0..15 * 2 {
+            assert_eq!(dcc.tick().unwrap(), ONE_MICROS);
+        }
+        assert_eq!(dcc.tick().unwrap(), ZERO_MICROS);
+        assert!(!dcc.output.polarity);
+        assert_eq!(dcc.tick().unwrap(), 9900);
+        assert!(dcc.output.polarity);
+
+        // half reverse throttle stretches the first half instead
+        dcc.set_analogue_throttle(-50).unwrap();
+        for _ in 0..8 * 2 {
+            assert_eq!(dcc.tick().unwrap(), ONE_MICROS);
+        }
+        assert_eq!(dcc.tick().unwrap(), 100 + 4900);
+        assert!(!dcc.output.polarity);
+        assert_eq!(dcc.tick().unwrap(), ZERO_MICROS);
+
+        // no stretching when the throttle is zero
+        dcc.set_analogue_throttle(0).unwrap();
+        let mut idle = SerialiseBuffer::default();
+        let idle_len = Idle.serialise(&mut idle).unwrap();
+        capture_packet(&mut dcc);
+        assert_eq!(capture_packet(&mut dcc)[..idle_len], idle[..idle_len]);
+    }
+
+    #[test]
+    fn railcom_cutout() {
+        let config = Config::builder().cutout(Cutout::default()).build();
+        let output = MockOutput {
+            supports_cutout: true,
+            ..Default::default()
+        };
+        let mut dcc: DccInterruptHandler<_> =
+            DccInterruptHandler::new(output, config);
+
+        let mut idle = SerialiseBuffer::default();
+        let idle_len = Idle.serialise(&mut idle).unwrap();
+        for _ in 0..idle_len * 2 - 1 {
+            dcc.tick().unwrap();
+            assert!(!dcc.output.cutout);
+        }
+
+        // the second half of the end bit is no longer than any other
+        assert_eq!(dcc.tick().unwrap(), ONE_MICROS);
+        assert!(dcc.output.polarity);
+
+        // the cutout starts shortly after the end bit
+        assert_eq!(dcc.tick().unwrap(), 29);
+        assert!(!dcc.output.polarity);
+        assert!(!dcc.output.cutout);
+        assert_eq!(dcc.tick().unwrap(), 471 - 29);
+        assert!(dcc.output.cutout);
+
+        // followed by the preamble of the next packet
+        assert_eq!(dcc.tick().unwrap(), ONE_MICROS);
+        assert!(!dcc.output.cutout);
+        assert!(!dcc.output.polarity);
+    }
+
+    #[test]
+    fn no_cutout_if_unsupported() {
+        let config = Config::builder().cutout(Cutout::default()).build();
+        let mut dcc: DccInterruptHandler<_> =
+            DccInterruptHandler::new(MockOutput::default(), config);
+
+        let mut idle = SerialiseBuffer::default();
+        let idle_len = Idle.serialise(&mut idle).unwrap();
+        for _ in 0..idle_len * 2 {
+            dcc.tick().unwrap();
+        }
+        assert_eq!(dcc.tick().unwrap(), ONE_MICROS);
+    }
+
+    #[test]
+    fn dead_time_between_polarity_changes() {
+        let output = MockOutput {
+            dead_time_micros: 2,
+            ..Default::default()
+        };
+        let mut dcc: DccInterruptHandler<_> =
+            DccInterruptHandler::new(output, Config::default());
+
+        // each bit-half is split into the dead time and the remainder
+        for polarity in [false, true, false] {
+            assert_eq!(dcc.tick().unwrap(), 2);
+            assert!(dcc.output.released);
+            assert_eq!(dcc.tick().unwrap(), ONE_MICROS - 2);
+            assert!(!dcc.output.released);
+            assert_eq!(dcc.output.polarity, polarity);
+        }
+    }
+
+    #[test]
+    fn switch_power() {
+        let mut dcc: DccInterruptHandler<_> =
+            DccInterruptHandler::new(MockOutput::default(), Config::default());
+        dcc.set_power(true).unwrap();
+        assert!(dcc.output.enabled);
+        dcc.set_power(false).unwrap();
+        assert!(!dcc.output.enabled);
+    }
+
+    #[test]
+    fn extract_packet_address() {
+        let mut buf = SerialiseBuffer::default();
+        Idle.serialise(&mut buf).unwrap();
+        assert_eq!(packet_address(&buf), None);
+
+        let len = packets::BasicAccessory::builder()
+            .board_address(1, 0)
+            .unwrap()
+            .build()
+            .unwrap()
+            .serialise(&mut buf)
+            .unwrap();
+        assert_eq!(packet_address(&buf[..len]), Some([0x81, 0xf9]));
+
+        assert_eq!(packet_address([0xff, 0xff].view_bits()), None);
     }
 }
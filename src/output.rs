@@ -0,0 +1,439 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Output drivers which turn the track polarity chosen by the
+//! `DccInterruptHandler` into pin states for a particular kind of booster.
+//!
+//! * `SinglePin` drives a single direction pin, for boosters which invert
+//!   the signal for the other rail themselves
+//! * `MotorShield` drives a direction pin along with brake and enable pins,
+//!   as found on e.g. the Arduino motor shield
+//! * `HBridge` drives the two complementary inputs and the enable pin of an
+//!   H-bridge such as the L298, BTS7960 or DRV8874
+//!
+//! The drivers take pins implementing the embedded-hal 0.2 `OutputPin`
+//! trait. With the `embedded-hal-1` feature enabled, pins implementing the
+//! embedded-hal 1.0 `OutputPin` trait can be used by wrapping them in an
+//! `Eh1Pin`, and every output error must implement the embedded-hal 1.0
+//! `digital::Error` trait. 0.2 pins can then only be used if their error
+//! type implements it too, as `Infallible` does.
+
+use crate::Error;
+use core::marker::PhantomData;
+use embedded_hal::digital::v2::OutputPin;
+
+/// Longest dead time which may be inserted between switching off one side
+/// of an H-bridge and switching on the other
+pub const MAX_DEAD_TIME_MICROS: u32 = 10;
+
+/// Bound on the errors returned by a `DccOutput`: the embedded-hal 1.0
+/// `digital::Error` trait, so that the `kind` of an error returned by
+/// `DccInterruptHandler::tick` can be inspected
+#[cfg(feature = "embedded-hal-1")]
+pub trait OutputError: embedded_hal_1::digital::Error {}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<E: embedded_hal_1::digital::Error> OutputError for E {}
+
+/// Bound on the errors returned by a `DccOutput`. Any type may be used
+/// unless the `embedded-hal-1` feature is enabled, in which case the error
+/// must implement the embedded-hal 1.0 `digital::Error` trait.
+#[cfg(not(feature = "embedded-hal-1"))]
+pub trait OutputError {}
+
+#[cfg(not(feature = "embedded-hal-1"))]
+impl<E> OutputError for E {}
+
+/// Hardware driving the track, as controlled by the `DccInterruptHandler`
+pub trait DccOutput {
+    /// Error type returned when setting the output fails
+    type Error: OutputError;
+
+    /// Drive the track with the given polarity. The handler sets the
+    /// polarity to `false` for the first half of each bit and to `true`
+    /// for the second half.
+    fn set_polarity(&mut self, polarity: bool) -> Result<(), Self::Error>;
+
+    /// Time to wait between calling `release` and `set_polarity` when
+    /// switching polarity, to avoid shoot-through
+    fn dead_time_micros(&self) -> u32 {
+        0
+    }
+
+    /// Stop driving the track ahead of a change in polarity. Only called
+    /// if `dead_time_micros` is non-zero.
+    fn release(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Returns `true` if the output is able to generate a RailCom cutout
+    fn supports_cutout(&self) -> bool {
+        false
+    }
+
+    /// Start or end the RailCom cutout, during which the track is shorted
+    fn set_cutout(&mut self, _active: bool) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Switch the track power on or off, e.g. after a short circuit
+    fn set_enabled(&mut self, _enabled: bool) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Placeholder for an optional pin which is not connected. Setting its
+/// state does nothing.
+pub struct NoPin<E>(PhantomData<E>);
+
+impl<E> NoPin<E> {
+    /// Create a placeholder pin
+    pub fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<E> Default for NoPin<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E> OutputPin for NoPin<E> {
+    type Error = E;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Adapter allowing a pin which implements the embedded-hal 1.0
+/// `OutputPin` trait to be used with the output drivers. Errors from the
+/// pin are passed through unchanged, so the error returned by
+/// `DccInterruptHandler::tick` is the pin's `ErrorType::Error`.
+#[cfg(feature = "embedded-hal-1")]
+pub struct Eh1Pin<P> {
+    pin: P,
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<P: embedded_hal_1::digital::OutputPin> Eh1Pin<P> {
+    /// Wrap an embedded-hal 1.0 output pin
+    pub fn new(pin: P) -> Self {
+        Self { pin }
+    }
+
+    /// Release the pin
+    pub fn free(self) -> P {
+        self.pin
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<P: embedded_hal_1::digital::OutputPin> OutputPin for Eh1Pin<P> {
+    type Error = P::Error;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.pin.set_low()
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.pin.set_high()
+    }
+}
+
+/// Output which drives a single pin, e.g. a motor shield's `direction`
+/// pin. This cannot generate a RailCom cutout or switch off the track
+/// power.
+pub struct SinglePin<P> {
+    pin: P,
+}
+
+impl<P: OutputPin> SinglePin<P> {
+    /// Drive the track polarity using `pin`
+    pub fn new(pin: P) -> Self {
+        Self { pin }
+    }
+
+    /// Release the pin
+    pub fn free(self) -> P {
+        self.pin
+    }
+}
+
+impl<P> DccOutput for SinglePin<P>
+where
+    P: OutputPin,
+    P::Error: OutputError,
+{
+    type Error = P::Error;
+
+    fn set_polarity(&mut self, polarity: bool) -> Result<(), Self::Error> {
+        if polarity {
+            self.pin.set_high()
+        } else {
+            self.pin.set_low()
+        }
+    }
+}
+
+/// Output for motor shields which have `direction`, `brake` and `enable`
+/// pins. The brake is applied for the duration of the RailCom cutout.
+/// Use `NoPin` for the enable pin if it is not connected.
+pub struct MotorShield<D, B, E> {
+    direction: D,
+    brake: B,
+    enable: E,
+}
+
+impl<D, B, E> MotorShield<D, B, E>
+where
+    D: OutputPin,
+    B: OutputPin<Error = D::Error>,
+    E: OutputPin<Error = D::Error>,
+{
+    /// Drive a motor shield using the given pins
+    pub fn new(direction: D, brake: B, enable: E) -> Self {
+        Self {
+            direction,
+            brake,
+            enable,
+        }
+    }
+
+    /// Release the pins
+    pub fn free(self) -> (D, B, E) {
+        (self.direction, self.brake, self.enable)
+    }
+}
+
+impl<D, B, E> DccOutput for MotorShield<D, B, E>
+where
+    D: OutputPin,
+    D::Error: OutputError,
+    B: OutputPin<Error = D::Error>,
+    E: OutputPin<Error = D::Error>,
+{
+    type Error = D::Error;
+
+    fn set_polarity(&mut self, polarity: bool) -> Result<(), Self::Error> {
+        if polarity {
+            self.direction.set_high()
+        } else {
+            self.direction.set_low()
+        }
+    }
+
+    fn supports_cutout(&self) -> bool {
+        true
+    }
+
+    fn set_cutout(&mut self, active: bool) -> Result<(), Self::Error> {
+        if active {
+            self.brake.set_high()
+        } else {
+            self.brake.set_low()
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) -> Result<(), Self::Error> {
+        if enabled {
+            self.enable.set_high()
+        } else {
+            self.enable.set_low()
+        }
+    }
+}
+
+/// Output for H-bridges with two complementary inputs and an enable pin.
+/// During the RailCom cutout and the optional dead time both inputs are
+/// held low, shorting the track through the low side of the bridge. Use
+/// `NoPin` for the enable pin if it is not connected.
+pub struct HBridge<A, B, E> {
+    in1: A,
+    in2: B,
+    enable: E,
+    dead_time_micros: u32,
+}
+
+impl<A, B, E> HBridge<A, B, E>
+where
+    A: OutputPin,
+    B: OutputPin<Error = A::Error>,
+    E: OutputPin<Error = A::Error>,
+{
+    /// Drive an H-bridge using the given pins. `in1` is driven high for
+    /// the second half of each bit and `in2` for the first half.
+    pub fn new(in1: A, in2: B, enable: E) -> Self {
+        Self {
+            in1,
+            in2,
+            enable,
+            dead_time_micros: 0,
+        }
+    }
+
+    /// Hold both inputs low for `micros` between each change of polarity.
+    /// Returns `Error::InvalidTiming` if the dead time is longer than
+    /// `MAX_DEAD_TIME_MICROS`.
+    pub fn with_dead_time(mut self, micros: u32) -> Result<Self, Error> {
+        if micros > MAX_DEAD_TIME_MICROS {
+            return Err(Error::InvalidTiming);
+        }
+        self.dead_time_micros = micros;
+        Ok(self)
+    }
+
+    /// Release the pins
+    pub fn free(self) -> (A, B, E) {
+        (self.in1, self.in2, self.enable)
+    }
+}
+
+impl<A, B, E> DccOutput for HBridge<A, B, E>
+where
+    A: OutputPin,
+    A::Error: OutputError,
+    B: OutputPin<Error = A::Error>,
+    E: OutputPin<Error = A::Error>,
+{
+    type Error = A::Error;
+
+    fn set_polarity(&mut self, polarity: bool) -> Result<(), Self::Error> {
+        // always switch the active input off first
+        if polarity {
+            self.in2.set_low()?;
+            self.in1.set_high()
+        } else {
+            self.in1.set_low()?;
+            self.in2.set_high()
+        }
+    }
+
+    fn dead_time_micros(&self) -> u32 {
+        self.dead_time_micros
+    }
+
+    fn release(&mut self) -> Result<(), Self::Error> {
+        self.in1.set_low()?;
+        self.in2.set_low()
+    }
+
+    fn supports_cutout(&self) -> bool {
+        true
+    }
+
+    fn set_cutout(&mut self, active: bool) -> Result<(), Self::Error> {
+        if active {
+            self.release()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) -> Result<(), Self::Error> {
+        if enabled {
+            self.enable.set_high()
+        } else {
+            self.release()?;
+            self.enable.set_low()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::MockPin;
+
+    #[test]
+    fn motor_shield() {
+        let mut output = MotorShield::new(
+            MockPin::default(),
+            MockPin::default(),
+            MockPin::default(),
+        );
+        assert!(output.supports_cutout());
+        output.set_enabled(true).unwrap();
+        output.set_polarity(true).unwrap();
+        output.set_cutout(true).unwrap();
+        let (direction, brake, enable) = output.free();
+        assert!(direction.state);
+        assert!(brake.state);
+        assert!(enable.state);
+    }
+
+    #[test]
+    fn h_bridge() {
+        let mut output =
+            HBridge::new(MockPin::default(), MockPin::default(), NoPin::new());
+        assert_eq!(output.dead_time_micros(), 0);
+
+        output.set_polarity(true).unwrap();
+        assert!(output.in1.state && !output.in2.state);
+        output.set_polarity(false).unwrap();
+        assert!(!output.in1.state && output.in2.state);
+        output.set_cutout(true).unwrap();
+        assert!(!output.in1.state && !output.in2.state);
+
+        assert!(output.with_dead_time(11).is_err());
+    }
+
+    #[cfg(feature = "embedded-hal-1")]
+    #[test]
+    fn embedded_hal_1_pins() {
+        use embedded_hal_1::digital::{self, ErrorKind, ErrorType};
+
+        #[derive(Debug)]
+        struct PinError;
+
+        impl digital::Error for PinError {
+            fn kind(&self) -> ErrorKind {
+                ErrorKind::Other
+            }
+        }
+
+        #[derive(Default)]
+        struct Eh1MockPin {
+            state: bool,
+        }
+
+        impl ErrorType for Eh1MockPin {
+            type Error = PinError;
+        }
+
+        impl digital::OutputPin for Eh1MockPin {
+            fn set_low(&mut self) -> Result<(), Self::Error> {
+                self.state = false;
+                Ok(())
+            }
+
+            fn set_high(&mut self) -> Result<(), Self::Error> {
+                if self.state {
+                    return Err(PinError);
+                }
+                self.state = true;
+                Ok(())
+            }
+        }
+
+        // the error of any output has a kind
+        fn kind<O: DccOutput>(error: O::Error) -> ErrorKind {
+            digital::Error::kind(&error)
+        }
+
+        let mut output = SinglePin::new(Eh1Pin::new(Eh1MockPin::default()));
+        output.set_polarity(true).unwrap();
+        let error = output.set_polarity(true).unwrap_err();
+        assert_eq!(
+            kind::<SinglePin<Eh1Pin<Eh1MockPin>>>(error),
+            ErrorKind::Other
+        );
+        assert!(output.free().free().state);
+    }
+}
@@ -7,33 +7,21 @@
 //!
 //! <https://www.nmra.org/sites/default/files/s-92-2004-07.pdf>
 
-use super::{Preamble, Result, SerialiseBuffer};
+use super::{Address, DccPacket, RawPacket, Result};
 use crate::Error;
-use bitvec::prelude::*;
-
-impl Default for Preamble {
-    fn default() -> Self {
-        Self(BitArray::from([0xff, 0xff]))
-    }
-}
 
 /// Possible directions, usually referenced to the "forward" direction
 /// of a loco
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub enum Direction {
     /// Forward
+    #[default]
     Forward,
     /// Backward
     Backward,
 }
 
-impl Default for Direction {
-    fn default() -> Self {
-        Self::Forward
-    }
-}
-
 impl Direction {
     /// Switches a direction to the opposite one
     pub fn toggle(&mut self) {
@@ -62,8 +50,10 @@ impl Direction {
 ///   ...   |   ...
 ///  1 1111 | speed 28 (0x1f)
 /// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct SpeedAndDirection {
-    address: u8,
+    address: Address,
     instruction: u8,
 }
 
@@ -74,23 +64,71 @@ impl SpeedAndDirection {
         SpeedAndDirectionBuilder::default()
     }
 
-    /// Serialise the packed into the provided buffer
-    pub fn serialise(&self, buf: &mut SerialiseBuffer) -> Result<usize> {
-        super::serialise(
-            &[
-                self.address,
-                self.instruction,
-                self.address ^ self.instruction,
-            ],
-            buf,
-        )
+    /// Decode a `01DCSSSS` speed and direction instruction sent to
+    /// `address`
+    pub(super) fn parse(address: Address, instruction: &[u8]) -> Option<Self> {
+        match *instruction {
+            [instruction] if instruction & 0xc0 == 0x40 => Some(Self {
+                address,
+                instruction,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The address of the loco
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// The five speed bits, reassembled into the `43210` order used by
+    /// the table above
+    fn speed_bits(&self) -> u8 {
+        (self.instruction & 0x0f) << 1 | (self.instruction >> 4) & 0x01
+    }
+
+    /// The speed step, between 0 and 28. An e-stop reads as speed 0.
+    pub fn speed(&self) -> u8 {
+        self.speed_bits().saturating_sub(3)
+    }
+
+    /// The direction of travel
+    pub fn direction(&self) -> Direction {
+        if self.instruction & 0b0010_0000 != 0 {
+            Direction::Forward
+        } else {
+            Direction::Backward
+        }
+    }
+
+    /// Returns `true` if this packet is an emergency stop
+    pub fn is_e_stop(&self) -> bool {
+        matches!(self.speed_bits(), 2 | 3)
+    }
+
+    /// The speed step for a decoder in 14 speed step mode, between 0 and
+    /// 14. In this mode the `C` bit is not part of the speed, but instead
+    /// switches the headlight.
+    pub fn speed_14(&self) -> u8 {
+        (self.instruction & 0x0f).saturating_sub(1)
+    }
+
+    /// The headlight state for a decoder in 14 speed step mode
+    pub fn headlight(&self) -> bool {
+        self.instruction & 0b0001_0000 != 0
+    }
+}
+
+impl DccPacket for SpeedAndDirection {
+    fn to_bytes(&self) -> RawPacket {
+        super::addressed_bytes(self.address, &[self.instruction])
     }
 }
 
 /// Builder used to construct a SpeedAndDirection packet
 #[derive(Default)]
 pub struct SpeedAndDirectionBuilder {
-    address: Option<u8>,
+    address: Option<Address>,
     speed: Option<u8>,
     e_stop: bool,
     direction: Option<Direction>,
@@ -101,12 +139,16 @@ impl SpeedAndDirectionBuilder {
     /// and 126. Returns `Error::InvalidAddress` if the provided address
     /// is outside this range.
     pub fn address(&mut self, address: u8) -> Result<&mut Self> {
-        if address == 0 || address > 0x7f {
-            Err(Error::InvalidAddress)
-        } else {
-            self.address = Some(address);
-            Ok(self)
-        }
+        self.address = Some(Address::short(address)?);
+        Ok(self)
+    }
+
+    /// Sets a long-mode (14-bit) address. In long mode the address has to
+    /// be between 0 and 10239. Returns `Error::InvalidAddress` if the
+    /// provided address is outside this range.
+    pub fn long_address(&mut self, address: u16) -> Result<&mut Self> {
+        self.address = Some(Address::long(address)?);
+        Ok(self)
     }
 
     /// Sets the speed. In short mode the speed has to be between 0 and
@@ -143,7 +185,7 @@ impl SpeedAndDirectionBuilder {
     /// * `address = 3`
     /// * `headlight = false`
     pub fn build(&mut self) -> SpeedAndDirection {
-        let address = self.address.unwrap_or(3);
+        let address = self.address.unwrap_or(Address::Short(3));
         // add the weird offset to the speed
         let speed = match self.speed {
             Some(0) | None => 0,
@@ -178,23 +220,25 @@ impl SpeedAndDirectionBuilder {
 /// all zero. All decoders will, upon receiving this packet, reset to their
 /// normal power-up state. Any speed or direction will be cleared and
 /// locomotives stopped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct Reset;
 
-impl Reset {
-    /// Serialise the packed into the provided buffer
-    pub fn serialise(&self, buf: &mut SerialiseBuffer) -> Result<usize> {
-        super::serialise(&[0x00, 0x00, 0x00], buf)
+impl DccPacket for Reset {
+    fn to_bytes(&self) -> RawPacket {
+        RawPacket::with_ecc(&[0x00, 0x00])
     }
 }
 
 /// An Idle packet is one in which the address is 0xff and instruction 0x00.
 /// Upon receiving this, a decoder performs no new action.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct Idle;
 
-impl Idle {
-    /// Serialise the packed into the provided buffer
-    pub fn serialise(&self, buf: &mut SerialiseBuffer) -> Result<usize> {
-        super::serialise(&[0xff, 0x00, 0xff], buf)
+impl DccPacket for Idle {
+    fn to_bytes(&self) -> RawPacket {
+        RawPacket::with_ecc(&[0xff, 0x00])
     }
 }
 
@@ -204,6 +248,8 @@ impl Idle {
 /// a BroadcastStop is being sent it is unlikely that direction settings
 /// will be important. (whereas a regular stop might wish to retain
 /// headlight states)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct BroadcastStop {
     float: bool,
 }
@@ -219,17 +265,42 @@ impl BroadcastStop {
         Self { float: true }
     }
 
-    /// Serialise the packed into the provided buffer
-    pub fn serialise(&self, buf: &mut SerialiseBuffer) -> Result<usize> {
+    /// Decode a stop instruction sent to the broadcast address, which may
+    /// be either a baseline or a 128 speed step stop or e-stop. Only the
+    /// "float" encoding produced by `BroadcastStop::float` is read as a
+    /// floating stop; any other stop or e-stop is immediate.
+    pub(super) fn parse(instruction: &[u8]) -> Option<Self> {
+        match *instruction {
+            [instruction] if instruction & 0xce == 0x40 => Some(Self {
+                float: instruction & 0x1f == 0x10,
+            }),
+            [0b0011_1111, speed] if speed & 0x7e == 0 => {
+                Some(Self::immediate())
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the locos should be brought to a floating stop
+    /// rather than stopped immediately
+    pub fn is_float(&self) -> bool {
+        self.float
+    }
+}
+
+impl DccPacket for BroadcastStop {
+    fn to_bytes(&self) -> RawPacket {
         let instr = if self.float { 0b0101_0000 } else { 0b0100_0000 };
 
-        super::serialise(&[0x00, instr, instr], buf)
+        RawPacket::with_ecc(&[0x00, instr])
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::packets::SerialiseBuffer;
+    use bitvec::prelude::*;
 
     fn display_serialise_buffer(buf: &SerialiseBuffer) {
         println!("{buf:?}");
@@ -272,7 +343,7 @@ mod test {
             .speed(14)?
             .direction(Direction::Forward)
             .build();
-        assert_eq!(pkt.address, 35);
+        assert_eq!(pkt.address, Address::Short(35));
         let expected = 0b0111_1000;
         eprintln!("Got instruction: {:08b}", pkt.instruction);
         eprintln!("Expected:        {expected:08b}");
@@ -314,6 +385,35 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn serialise_long_address_speed_and_direction() -> Result<()> {
+        let pkt = SpeedAndDirection::builder()
+            .long_address(1234)?
+            .speed(14)?
+            .direction(Direction::Forward)
+            .build();
+        let mut buf = SerialiseBuffer::default();
+        let len = pkt.serialise(&mut buf)?;
+        // address is 11AA AAAA AAAA AAAA = 1100 0100 1101 0010
+        // instruction is 0111 1000, ecc is 0110 1110
+        #[allow(clippy::unusual_byte_groupings)]
+        let expected_arr = [
+            0xff_u8,      // preamble
+            0b1111_1110,  // preamble + start
+            0b1100_0100,  // address[..8]
+            0b0_1101001,  // start + address[8..15]
+            0b0_0_011110, // address[15] + start + instr[..6]
+            0b00_0_01101, // instr[6..] + start + ecc[..5]
+            0b110_1_0000, // ecc[5..] + stop + 4 zeroes
+        ];
+        let mut expected = SerialiseBuffer::default();
+        expected[..52]
+            .copy_from_bitslice(&expected_arr.view_bits::<Msb0>()[..52]);
+        assert_eq!(len, 52);
+        assert_eq!(buf[..len], expected[..52]);
+        Ok(())
+    }
+
     #[test]
     fn serialise_reset_packet() -> Result<()> {
         let pkt = Reset;
@@ -4,3 +4,2098 @@
 
 //! This module provides types and serialisers for each "extended"
 //! packet type defined by the NMRA standard.
+//!
+//! <https://www.nmra.org/sites/default/files/standards/sandrp/pdf/s-9.2.1_2012_07.pdf>
+
+use super::{
+    CvAccess, DccPacket, Direction, InstructionType, RawPacket, Result,
+};
+use crate::Error;
+
+/// Address of a multi-function (mobile) decoder.
+///
+/// Short addresses are sent as a single `0AAAAAAA` byte, whereas long
+/// (a.k.a. "extended" or "four-digit") addresses are sent as the two
+/// bytes `11AAAAAA AAAAAAAA`, with the most significant bits first.
+///
+/// The variants may be constructed directly, but the values are not then
+/// range-checked; prefer `Address::short` and `Address::long`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum Address {
+    /// Short address, in the range 1-127
+    Short(u8),
+    /// Long address, in the range 0-10239
+    Long(u16),
+}
+
+impl Address {
+    /// Highest address which may be encoded in long mode. The first
+    /// address byte must not exceed `0b1110_0111`, as higher values are
+    /// reserved for future use.
+    pub const MAX_LONG: u16 = 10239;
+
+    /// Create a short-mode address. Returns `Error::InvalidAddress` if
+    /// the provided address is outside the range 1-127.
+    pub fn short(address: u8) -> Result<Self> {
+        if address == 0 || address > 0x7f {
+            Err(Error::InvalidAddress)
+        } else {
+            Ok(Self::Short(address))
+        }
+    }
+
+    /// Create a long-mode address. Returns `Error::InvalidAddress` if the
+    /// provided address is greater than 10239.
+    pub fn long(address: u16) -> Result<Self> {
+        if address > Self::MAX_LONG {
+            Err(Error::InvalidAddress)
+        } else {
+            Ok(Self::Long(address))
+        }
+    }
+
+    /// Whether the address is within the range which may be sent in its
+    /// mode
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Self::Short(address) => (1..=0x7f).contains(&address),
+            Self::Long(address) => address <= Self::MAX_LONG,
+        }
+    }
+
+    /// Write the address bytes into the start of `buf`, returning the
+    /// number of bytes written
+    pub(super) fn write_bytes(&self, buf: &mut [u8]) -> usize {
+        debug_assert!(self.is_valid(), "invalid address {:?}", self);
+        match *self {
+            Self::Short(address) => {
+                buf[0] = address & 0x7f;
+                1
+            }
+            Self::Long(address) => {
+                buf[0] = 0b1100_0000 | ((address >> 8) as u8 & 0x3f);
+                buf[1] = (address & 0x00ff) as u8;
+                2
+            }
+        }
+    }
+
+    /// Read a multi-function decoder address from the start of `bytes`,
+    /// returning the address and the number of bytes it occupied. Returns
+    /// `None` if the bytes do not start with a valid short or long
+    /// address.
+    pub(super) fn read_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        match *bytes {
+            [address @ 0x01..=0x7f, ..] => Some((Self::Short(address), 1)),
+            [high @ 0xc0..=0xe7, low, ..] => {
+                Some((Self::Long((high as u16 & 0x3f) << 8 | low as u16), 2))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Advanced Operations "128 Speed Step Control" packet. Used to command a
+/// loco to move in the given direction at one of 126 speed steps, which
+/// gives much finer control at low speeds than the 28-step
+/// `SpeedAndDirection` packet.
+///
+/// The instruction is two bytes wide: `0011 1111` followed by `DSSS SSSS`,
+/// where `D` is the direction and the speed is encoded as:
+/// ```ignore
+///  SSS SSSS | meaning
+///  ---------------------------------------------
+///  000 0000 | stop
+///  000 0001 | e-stop
+///  000 0010 | speed 1
+///    ...    |   ...
+///  111 1111 | speed 126
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct SpeedAndDirection128 {
+    address: Address,
+    speed: u8,
+}
+
+impl SpeedAndDirection128 {
+    /// Builder interface for `SpeedAndDirection128`. Use of the Builder
+    /// pattern ensures that only valid packets are produced.
+    pub fn builder() -> SpeedAndDirection128Builder {
+        SpeedAndDirection128Builder::default()
+    }
+
+    /// Decode a `0011 1111` 128 speed step instruction sent to `address`
+    pub(super) fn parse(address: Address, instruction: &[u8]) -> Option<Self> {
+        match *instruction {
+            [0b0011_1111, speed] => Some(Self { address, speed }),
+            _ => None,
+        }
+    }
+
+    /// The address of the loco
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// The speed step, between 0 and 126. An e-stop reads as speed 0.
+    pub fn speed(&self) -> u8 {
+        (self.speed & 0x7f).saturating_sub(1)
+    }
+
+    /// The direction of travel
+    pub fn direction(&self) -> Direction {
+        if self.speed & 0b1000_0000 != 0 {
+            Direction::Forward
+        } else {
+            Direction::Backward
+        }
+    }
+
+    /// Returns `true` if this packet is an emergency stop
+    pub fn is_e_stop(&self) -> bool {
+        self.speed & 0x7f == 0x01
+    }
+}
+
+impl DccPacket for SpeedAndDirection128 {
+    fn to_bytes(&self) -> RawPacket {
+        super::addressed_bytes(self.address, &[0b0011_1111, self.speed])
+    }
+}
+
+/// Builder used to construct a `SpeedAndDirection128` packet
+#[derive(Default)]
+pub struct SpeedAndDirection128Builder {
+    address: Option<Address>,
+    speed: Option<u8>,
+    e_stop: bool,
+    direction: Option<Direction>,
+}
+
+impl SpeedAndDirection128Builder {
+    /// Sets the address. In short mode the address has to be between 1
+    /// and 127. Returns `Error::InvalidAddress` if the provided address
+    /// is outside this range.
+    pub fn address(&mut self, address: u8) -> Result<&mut Self> {
+        self.address = Some(Address::short(address)?);
+        Ok(self)
+    }
+
+    /// Sets a long-mode (14-bit) address. In long mode the address has to
+    /// be between 0 and 10239. Returns `Error::InvalidAddress` if the
+    /// provided address is outside this range.
+    pub fn long_address(&mut self, address: u16) -> Result<&mut Self> {
+        self.address = Some(Address::long(address)?);
+        Ok(self)
+    }
+
+    /// Sets the speed, which has to be between 0 and 126. Returns
+    /// `Error::InvalidSpeed` if the provided speed is outside this range.
+    pub fn speed(&mut self, speed: u8) -> Result<&mut Self> {
+        if speed > 126 {
+            Err(Error::InvalidSpeed)
+        } else {
+            self.speed = Some(speed);
+            Ok(self)
+        }
+    }
+
+    /// Sets the direction
+    pub fn direction(&mut self, direction: Direction) -> &mut Self {
+        self.direction = Some(direction);
+        self
+    }
+
+    /// Sends the e-stop signal. Overrides any other set speed value
+    pub fn e_stop(&mut self, e_stop: bool) -> &mut Self {
+        self.e_stop = e_stop;
+        self
+    }
+
+    /// Build a `SpeedAndDirection128` packet using the provided values,
+    /// falling back to sensible defaults if not all fields have been
+    /// provided.
+    ///
+    /// Defaults:
+    /// * `speed = 0`
+    /// * `direction = Forward`
+    /// * `address = 3`
+    pub fn build(&mut self) -> SpeedAndDirection128 {
+        let address = self.address.unwrap_or(Address::Short(3));
+
+        // e-stop overrides other speed setting; otherwise skip over the
+        // e-stop value
+        let mut speed = if self.e_stop {
+            0x01
+        } else {
+            match self.speed {
+                Some(0) | None => 0,
+                Some(speed) => speed + 1,
+            }
+        };
+
+        if let Direction::Forward = self.direction.unwrap_or_default() {
+            speed |= 0b1000_0000;
+        }
+
+        SpeedAndDirection128 { address, speed }
+    }
+}
+
+/// On/off state of a multi-function decoder's functions F0-F68. Function
+/// 0 is the headlight, sometimes referred to as `FL`.
+///
+/// A throttle can hold one of these per loco and send the relevant
+/// `FunctionGroup`s whenever functions are switched; see
+/// `Functions::changed_groups`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Functions(u128);
+
+impl Functions {
+    /// Highest supported function number
+    pub const MAX: u8 = 68;
+
+    /// Create a set of functions which are all switched off
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Switch the given function on or off. Returns
+    /// `Error::InvalidFunction` if the function number is greater than
+    /// `Functions::MAX`.
+    pub fn set(&mut self, function: u8, on: bool) -> Result<&mut Self> {
+        if function > Self::MAX {
+            return Err(Error::InvalidFunction);
+        }
+        if on {
+            self.0 |= 1 << function;
+        } else {
+            self.0 &= !(1 << function);
+        }
+        Ok(self)
+    }
+
+    /// Returns whether the given function is switched on. Functions out of
+    /// range are always off.
+    pub fn get(&self, function: u8) -> bool {
+        function <= Self::MAX && self.0 & (1 << function) != 0
+    }
+
+    /// Returns whether any function in the given group is switched on
+    pub fn any(&self, group: FunctionGroup) -> bool {
+        self.0 & group.mask() != 0
+    }
+
+    /// Copy the states of the functions in `group` from `other`, leaving
+    /// the other functions unchanged, e.g. to apply a received
+    /// `FunctionControl` packet
+    pub fn update(&mut self, group: FunctionGroup, other: &Functions) {
+        let mask = group.mask();
+        self.0 = (self.0 & !mask) | (other.0 & mask);
+    }
+
+    /// Pull out `count` function bits starting at `first`, with the lowest
+    /// numbered function in the LSB
+    fn bits(&self, first: u8, count: u8) -> u8 {
+        ((self.0 >> first) & ((1 << count) - 1)) as u8
+    }
+
+    /// Set `count` function bits starting at `first` from `bits`, the
+    /// inverse of `Functions::bits`
+    fn set_bits(&mut self, first: u8, count: u8, bits: u8) {
+        let mask = ((1 << count) - 1) << first;
+        self.0 = (self.0 & !mask) | ((bits as u128) << first) & mask;
+    }
+
+    /// Returns the groups containing functions whose state differs between
+    /// `self` and `previous`, so that only those groups need to be sent
+    pub fn changed_groups(
+        &self,
+        previous: &Functions,
+    ) -> impl Iterator<Item = FunctionGroup> {
+        let changed = self.0 ^ previous.0;
+        FunctionGroup::ALL
+            .iter()
+            .copied()
+            .filter(move |group| changed & group.mask() != 0)
+    }
+}
+
+/// Groups of functions which are sent together in a single instruction
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum FunctionGroup {
+    /// Function Group One: `100 FL F4 F3 F2 F1`
+    F0ToF4,
+    /// Function Group Two, upper half: `1011 F8 F7 F6 F5`
+    F5ToF8,
+    /// Function Group Two, lower half: `1010 F12 F11 F10 F9`
+    F9ToF12,
+    /// Feature Expansion: `1101 1110` followed by `F20 .. F13`
+    F13ToF20,
+    /// Feature Expansion: `1101 1111` followed by `F28 .. F21`
+    F21ToF28,
+    /// Feature Expansion: `1101 1000` followed by `F36 .. F29`
+    F29ToF36,
+    /// Feature Expansion: `1101 1001` followed by `F44 .. F37`
+    F37ToF44,
+    /// Feature Expansion: `1101 1010` followed by `F52 .. F45`
+    F45ToF52,
+    /// Feature Expansion: `1101 1011` followed by `F60 .. F53`
+    F53ToF60,
+    /// Feature Expansion: `1101 1100` followed by `F68 .. F61`
+    F61ToF68,
+}
+
+impl FunctionGroup {
+    /// Every function group, in ascending order of function number
+    pub const ALL: [FunctionGroup; 10] = [
+        Self::F0ToF4,
+        Self::F5ToF8,
+        Self::F9ToF12,
+        Self::F13ToF20,
+        Self::F21ToF28,
+        Self::F29ToF36,
+        Self::F37ToF44,
+        Self::F45ToF52,
+        Self::F53ToF60,
+        Self::F61ToF68,
+    ];
+
+    /// Returns the number of the lowest function in this group and the
+    /// number of functions in the group
+    fn range(&self) -> (u8, u8) {
+        match self {
+            Self::F0ToF4 => (0, 5),
+            Self::F5ToF8 => (5, 4),
+            Self::F9ToF12 => (9, 4),
+            Self::F13ToF20 => (13, 8),
+            Self::F21ToF28 => (21, 8),
+            Self::F29ToF36 => (29, 8),
+            Self::F37ToF44 => (37, 8),
+            Self::F45ToF52 => (45, 8),
+            Self::F53ToF60 => (53, 8),
+            Self::F61ToF68 => (61, 8),
+        }
+    }
+
+    /// Mask selecting the functions in this group from a `Functions`
+    fn mask(&self) -> u128 {
+        let (first, count) = self.range();
+        ((1 << count) - 1) << first
+    }
+
+    /// Encode the function states of this group as an instruction,
+    /// returning the instruction bytes and their length
+    fn instruction(&self, functions: &Functions) -> ([u8; 2], usize) {
+        let (first, count) = self.range();
+        let expansion = |opcode| ([opcode, functions.bits(first, count)], 2);
+        match self {
+            Self::F0ToF4 => (
+                [
+                    0b1000_0000
+                        | functions.bits(0, 1) << 4
+                        | functions.bits(1, 4),
+                    0,
+                ],
+                1,
+            ),
+            Self::F5ToF8 => ([0b1011_0000 | functions.bits(5, 4), 0], 1),
+            Self::F9ToF12 => ([0b1010_0000 | functions.bits(9, 4), 0], 1),
+            Self::F13ToF20 => expansion(0b1101_1110),
+            Self::F21ToF28 => expansion(0b1101_1111),
+            Self::F29ToF36 => expansion(0b1101_1000),
+            Self::F37ToF44 => expansion(0b1101_1001),
+            Self::F45ToF52 => expansion(0b1101_1010),
+            Self::F53ToF60 => expansion(0b1101_1011),
+            Self::F61ToF68 => expansion(0b1101_1100),
+        }
+    }
+
+    /// Decode a function group instruction into the group and the states
+    /// of its functions. Functions outside of the group are left off.
+    fn decode(instruction: &[u8]) -> Option<(Self, Functions)> {
+        let (group, bits) = match *instruction {
+            [instr] if instr & 0xe0 == 0x80 => {
+                (Self::F0ToF4, (instr & 0x0f) << 1 | (instr >> 4) & 0x01)
+            }
+            [instr] if instr & 0xf0 == 0xb0 => (Self::F5ToF8, instr & 0x0f),
+            [instr] if instr & 0xf0 == 0xa0 => (Self::F9ToF12, instr & 0x0f),
+            [0b1101_1110, bits] => (Self::F13ToF20, bits),
+            [0b1101_1111, bits] => (Self::F21ToF28, bits),
+            [0b1101_1000, bits] => (Self::F29ToF36, bits),
+            [0b1101_1001, bits] => (Self::F37ToF44, bits),
+            [0b1101_1010, bits] => (Self::F45ToF52, bits),
+            [0b1101_1011, bits] => (Self::F53ToF60, bits),
+            [0b1101_1100, bits] => (Self::F61ToF68, bits),
+            _ => return None,
+        };
+        let (first, count) = group.range();
+        let mut functions = Functions::new();
+        functions.set_bits(first, count, bits);
+        Some((group, functions))
+    }
+}
+
+/// Function control packet. Sets the state of one `FunctionGroup` of a
+/// multi-function decoder's functions; functions outside of that group are
+/// left unchanged.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct FunctionControl {
+    address: Address,
+    group: FunctionGroup,
+    functions: Functions,
+}
+
+impl FunctionControl {
+    /// Builder interface for `FunctionControl`
+    pub fn builder() -> FunctionControlBuilder {
+        FunctionControlBuilder::default()
+    }
+
+    /// Decode a function group instruction sent to `address`
+    pub(super) fn parse(address: Address, instruction: &[u8]) -> Option<Self> {
+        let (group, functions) = FunctionGroup::decode(instruction)?;
+        Some(Self {
+            address,
+            group,
+            functions,
+        })
+    }
+
+    /// The address of the loco
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// The group of functions controlled by this packet
+    pub fn group(&self) -> FunctionGroup {
+        self.group
+    }
+
+    /// The function states. Only the functions in `group` are meaningful.
+    pub fn functions(&self) -> Functions {
+        self.functions
+    }
+}
+
+impl DccPacket for FunctionControl {
+    fn to_bytes(&self) -> RawPacket {
+        let (instruction, len) = self.group.instruction(&self.functions);
+        super::addressed_bytes(self.address, &instruction[..len])
+    }
+}
+
+/// Builder used to construct a `FunctionControl` packet
+#[derive(Default)]
+pub struct FunctionControlBuilder {
+    address: Option<Address>,
+    group: Option<FunctionGroup>,
+    functions: Functions,
+}
+
+impl FunctionControlBuilder {
+    /// Sets the address. In short mode the address has to be between 1
+    /// and 127. Returns `Error::InvalidAddress` if the provided address
+    /// is outside this range.
+    pub fn address(&mut self, address: u8) -> Result<&mut Self> {
+        self.address = Some(Address::short(address)?);
+        Ok(self)
+    }
+
+    /// Sets a long-mode (14-bit) address. In long mode the address has to
+    /// be between 0 and 10239. Returns `Error::InvalidAddress` if the
+    /// provided address is outside this range.
+    pub fn long_address(&mut self, address: u16) -> Result<&mut Self> {
+        self.address = Some(Address::long(address)?);
+        Ok(self)
+    }
+
+    /// Sets which group of functions the packet will control
+    pub fn group(&mut self, group: FunctionGroup) -> &mut Self {
+        self.group = Some(group);
+        self
+    }
+
+    /// Sets the function states. Only the functions in the selected group
+    /// are sent. Defaults to all functions off.
+    pub fn functions(&mut self, functions: Functions) -> &mut Self {
+        self.functions = functions;
+        self
+    }
+
+    /// Build the `FunctionControl` packet. Returns `Error::MissingField` if
+    /// the address or the function group has not been set
+    pub fn build(&mut self) -> Result<FunctionControl> {
+        Ok(FunctionControl {
+            address: self.address.ok_or(Error::MissingField)?,
+            group: self.group.ok_or(Error::MissingField)?,
+            functions: self.functions,
+        })
+    }
+}
+
+/// Operations-mode ("Programming on the Main") configuration variable
+/// access packet, using the long form of the instruction:
+/// `1110 CCAA AAAAAAAA DDDDDDDD`. This allows a loco's CVs to be read back
+/// or modified without moving it to the programming track.
+///
+/// The standard requires the decoder to receive two identical packets
+/// before acting on a write, so this packet should be sent at least twice.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct ProgrammingOnMain {
+    address: Address,
+    typ: InstructionType,
+    cv_address: u16,
+}
+
+impl ProgrammingOnMain {
+    /// Create a builder for the `ProgrammingOnMain` packet
+    pub fn builder() -> ProgrammingOnMainBuilder {
+        ProgrammingOnMainBuilder::default()
+    }
+
+    /// Decode a long-form CV access instruction sent to `address`
+    pub(super) fn parse(address: Address, instruction: &[u8]) -> Option<Self> {
+        match *instruction {
+            [first, second, data] if first & 0xf0 == 0b1110_0000 => {
+                let (typ, cv_address) =
+                    InstructionType::decode([first, second, data])?;
+                Some(Self {
+                    address,
+                    typ,
+                    cv_address,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// The address of the loco
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// The write or verify operation to perform on the CV
+    pub fn instruction_type(&self) -> InstructionType {
+        self.typ
+    }
+
+    /// The CV number, between 1 and 1024
+    pub fn cv_address(&self) -> u16 {
+        self.cv_address + 1
+    }
+}
+
+impl DccPacket for ProgrammingOnMain {
+    fn to_bytes(&self) -> RawPacket {
+        let mut instruction = self.typ.encode(self.cv_address);
+        instruction[0] |= 0b1110_0000;
+        super::addressed_bytes(self.address, &instruction)
+    }
+
+    fn repeats(&self) -> u8 {
+        // decoders only act on the second of two identical packets
+        2
+    }
+}
+
+/// Builder struct for `ProgrammingOnMain` packets. Ensures that only valid
+/// packets are created
+#[derive(Default)]
+pub struct ProgrammingOnMainBuilder {
+    address: Option<Address>,
+    cv: CvAccess,
+}
+
+impl ProgrammingOnMainBuilder {
+    /// Sets the address. In short mode the address has to be between 1
+    /// and 127. Returns `Error::InvalidAddress` if the provided address
+    /// is outside this range.
+    pub fn address(&mut self, address: u8) -> Result<&mut Self> {
+        self.address = Some(Address::short(address)?);
+        Ok(self)
+    }
+
+    /// Sets a long-mode (14-bit) address. In long mode the address has to
+    /// be between 0 and 10239. Returns `Error::InvalidAddress` if the
+    /// provided address is outside this range.
+    pub fn long_address(&mut self, address: u16) -> Result<&mut Self> {
+        self.address = Some(Address::long(address)?);
+        Ok(self)
+    }
+
+    /// Set the CV number, between 1 and 1024. Returns
+    /// `Error::InvalidAddress` if the CV address does not fit into 10 bits.
+    ///
+    /// As with the service-mode `Instruction`, the transmitted address is
+    /// one less than the CV number.
+    pub fn cv_address(&mut self, cv_address: u16) -> Result<&mut Self> {
+        self.cv.cv_address(cv_address)?;
+        Ok(self)
+    }
+
+    /// Create a `WriteCvByte` packet with the provided byte value
+    pub fn write_byte(&mut self, value: u8) -> &mut Self {
+        self.cv.write_byte(value);
+        self
+    }
+
+    /// Create a `VerifyCvByte` packet with the provided byte value
+    pub fn verify_byte(&mut self, value: u8) -> &mut Self {
+        self.cv.verify_byte(value);
+        self
+    }
+
+    /// Create a `WriteCvBit` packet with the provided bit offset and value
+    pub fn write_bit(&mut self, offset: u8, value: bool) -> Result<&mut Self> {
+        self.cv.write_bit(offset, value)?;
+        Ok(self)
+    }
+
+    /// Create a `VerifyCvBit` packet with the provided bit offset and value
+    pub fn verify_bit(&mut self, offset: u8, value: bool) -> Result<&mut Self> {
+        self.cv.verify_bit(offset, value)?;
+        Ok(self)
+    }
+
+    /// Validate that all fields are present and return a
+    /// `ProgrammingOnMain` packet
+    pub fn build(&mut self) -> Result<ProgrammingOnMain> {
+        let address = self.address.ok_or(Error::MissingField)?;
+        let (typ, cv_address) = self.cv.build()?;
+        Ok(ProgrammingOnMain {
+            address,
+            typ,
+            cv_address,
+        })
+    }
+}
+
+/// Commanded position of a turnout or other two-output accessory
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum TurnoutDirection {
+    /// Closed, a.k.a. "normal" or "straight"
+    #[default]
+    Closed,
+    /// Thrown, a.k.a. "reverse" or "diverging"
+    Thrown,
+}
+
+/// Encode a 9-bit accessory decoder address into the `10AAAAAA 1AAA----`
+/// bytes shared by the basic accessory packets. The upper three address
+/// bits are transmitted in ones-complement form.
+fn accessory_address_bytes(address: u16) -> [u8; 2] {
+    [
+        0b1000_0000 | (address & 0x3f) as u8,
+        0b1000_0000 | (((!address >> 6) & 0x07) as u8) << 4,
+    ]
+}
+
+/// Encode an 11-bit extended accessory decoder address into the
+/// `10AAAAAA 0AAA0AA1` bytes shared by the extended accessory packets. The
+/// upper nine bits are encoded as for `accessory_address_bytes`, with the
+/// basic accessory flag bit cleared.
+fn extended_accessory_address_bytes(address: u16) -> [u8; 2] {
+    let [first, second] = accessory_address_bytes(address >> 2);
+    [
+        first,
+        (second & 0b0111_0000) | ((address & 0x03) as u8) << 1 | 0x01,
+    ]
+}
+
+/// Decode the 9-bit board address from the first two bytes of an
+/// accessory packet, the inverse of `accessory_address_bytes`
+fn accessory_address(first: u8, second: u8) -> u16 {
+    (first & 0x3f) as u16 | (((!second >> 4) & 0x07) as u16) << 6
+}
+
+/// Basic Accessory Decoder packet: `10AAAAAA 1AAACDDD`. Used to switch one
+/// output of a pair on an accessory decoder, such as a turnout motor or an
+/// uncoupler.
+///
+/// Each decoder has a 9-bit board address and controls four output pairs,
+/// selected by the upper two `D` bits; the lowest `D` bit selects which
+/// output of the pair is driven and `C` activates or deactivates it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct BasicAccessory {
+    address: u16,
+    output_pair: u8,
+    activate: bool,
+    direction: TurnoutDirection,
+}
+
+impl BasicAccessory {
+    /// Highest board address; 511 is the broadcast address
+    pub const MAX_BOARD_ADDRESS: u16 = 511;
+    /// Board address which is acted on by every basic accessory decoder
+    pub const BROADCAST_ADDRESS: u16 = 511;
+    /// Highest user-facing turnout number. The four output pairs after it
+    /// would be on the broadcast address, so are not individual turnouts.
+    pub const MAX_TURNOUT: u16 = 2040;
+
+    /// Builder interface for `BasicAccessory`
+    pub fn builder() -> BasicAccessoryBuilder {
+        BasicAccessoryBuilder::default()
+    }
+
+    /// Decode the bytes of a `BasicAccessory` packet, excluding the error
+    /// detection byte
+    pub(super) fn parse(bytes: &[u8]) -> Option<Self> {
+        match *bytes {
+            [address, instr] if address & 0xc0 == 0x80 && instr & 0x80 != 0 => {
+                Some(Self {
+                    address: accessory_address(address, instr),
+                    output_pair: (instr >> 1) & 0x03,
+                    activate: instr & 0x08 != 0,
+                    direction: if instr & 0x01 != 0 {
+                        TurnoutDirection::Closed
+                    } else {
+                        TurnoutDirection::Thrown
+                    },
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// The 9-bit board address
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+
+    /// The output pair on the board, between 0 and 3
+    pub fn output_pair(&self) -> u8 {
+        self.output_pair
+    }
+
+    /// Returns `true` if the output is activated rather than deactivated
+    pub fn activate(&self) -> bool {
+        self.activate
+    }
+
+    /// Which output of the pair is driven
+    pub fn direction(&self) -> TurnoutDirection {
+        self.direction
+    }
+}
+
+impl DccPacket for BasicAccessory {
+    fn to_bytes(&self) -> RawPacket {
+        let [address, mut instr] = accessory_address_bytes(self.address);
+        instr |= (self.activate as u8) << 3;
+        instr |= self.output_pair << 1;
+        if let TurnoutDirection::Closed = self.direction {
+            instr |= 0x01;
+        }
+
+        RawPacket::with_ecc(&[address, instr])
+    }
+}
+
+/// Builder used to construct a `BasicAccessory` packet
+pub struct BasicAccessoryBuilder {
+    address: Option<(u16, u8)>,
+    activate: bool,
+    direction: TurnoutDirection,
+}
+
+impl Default for BasicAccessoryBuilder {
+    fn default() -> Self {
+        Self {
+            address: None,
+            activate: true,
+            direction: TurnoutDirection::default(),
+        }
+    }
+}
+
+impl BasicAccessoryBuilder {
+    /// Sets the 9-bit board address (0-511) and the output pair (0-3) on
+    /// that board. Returns `Error::InvalidAddress` if either is out of
+    /// range.
+    pub fn board_address(
+        &mut self,
+        address: u16,
+        output_pair: u8,
+    ) -> Result<&mut Self> {
+        if address > BasicAccessory::MAX_BOARD_ADDRESS || output_pair > 3 {
+            Err(Error::InvalidAddress)
+        } else {
+            self.address = Some((address, output_pair));
+            Ok(self)
+        }
+    }
+
+    /// Sets the address from a user-facing turnout number (1-2040), as
+    /// displayed by most throttles. Turnouts 1-4 are the four output pairs
+    /// of board 1, turnouts 5-8 are on board 2 and so on. Returns
+    /// `Error::InvalidAddress` if the turnout number is out of range.
+    ///
+    /// Numbers 2041-2044 are sometimes shown by throttles, but they fall on
+    /// the broadcast address; use `broadcast` to address every decoder.
+    pub fn turnout(&mut self, turnout: u16) -> Result<&mut Self> {
+        if turnout == 0 || turnout > BasicAccessory::MAX_TURNOUT {
+            Err(Error::InvalidAddress)
+        } else {
+            let turnout = turnout - 1;
+            self.board_address(turnout / 4 + 1, (turnout % 4) as u8)
+        }
+    }
+
+    /// Address the output pair (0-3) on every basic accessory decoder at
+    /// once, using the broadcast address. Returns `Error::InvalidAddress`
+    /// if the output pair is out of range.
+    pub fn broadcast(&mut self, output_pair: u8) -> Result<&mut Self> {
+        self.board_address(BasicAccessory::BROADCAST_ADDRESS, output_pair)
+    }
+
+    /// Activate (`true`) or deactivate (`false`) the output. Defaults to
+    /// activate.
+    pub fn activate(&mut self, activate: bool) -> &mut Self {
+        self.activate = activate;
+        self
+    }
+
+    /// Sets which output of the pair to drive. Defaults to `Closed`
+    pub fn direction(&mut self, direction: TurnoutDirection) -> &mut Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Build the `BasicAccessory` packet. Returns `Error::MissingField` if
+    /// the address has not been set
+    pub fn build(&mut self) -> Result<BasicAccessory> {
+        let (address, output_pair) = self.address.ok_or(Error::MissingField)?;
+        Ok(BasicAccessory {
+            address,
+            output_pair,
+            activate: self.activate,
+            direction: self.direction,
+        })
+    }
+}
+
+/// Extended Accessory Decoder Control packet:
+/// `10AAAAAA 0AAA0AA1 XXXXXXXX`. Used to send an aspect number to an
+/// extended accessory decoder, typically a signal.
+///
+/// The 11-bit output address is made up of the 9-bit board address (split
+/// across both bytes as for `BasicAccessory`) followed by the two `AA` bits
+/// in the second byte. The meaning of each aspect number is defined by the
+/// decoder, except that aspect 0 is always the most restrictive ("stop").
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct ExtendedAccessory {
+    address: u16,
+    aspect: u8,
+}
+
+impl ExtendedAccessory {
+    /// Highest extended accessory address; this is the broadcast address
+    pub const BROADCAST_ADDRESS: u16 = 0x07ff;
+
+    /// Builder interface for `ExtendedAccessory`
+    pub fn builder() -> ExtendedAccessoryBuilder {
+        ExtendedAccessoryBuilder::default()
+    }
+
+    /// Decode the 11-bit output address from the `10AAAAAA 0AAA0AA1`
+    /// address bytes of an extended accessory packet
+    fn read_address(first: u8, second: u8) -> Option<u16> {
+        if first & 0xc0 != 0x80 || second & 0x89 != 0x01 {
+            return None;
+        }
+        Some(
+            accessory_address(first, second) << 2
+                | ((second >> 1) & 0x03) as u16,
+        )
+    }
+
+    /// Decode the bytes of an `ExtendedAccessory` packet, excluding the
+    /// error detection byte
+    pub(super) fn parse(bytes: &[u8]) -> Option<Self> {
+        match *bytes {
+            [first, second, aspect] => Some(Self {
+                address: Self::read_address(first, second)?,
+                aspect,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The 11-bit output address
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+
+    /// The aspect number
+    pub fn aspect(&self) -> u8 {
+        self.aspect
+    }
+}
+
+impl DccPacket for ExtendedAccessory {
+    fn to_bytes(&self) -> RawPacket {
+        let [address, instr] = extended_accessory_address_bytes(self.address);
+        RawPacket::with_ecc(&[address, instr, self.aspect])
+    }
+}
+
+/// Builder used to construct an `ExtendedAccessory` packet
+#[derive(Default)]
+pub struct ExtendedAccessoryBuilder {
+    address: Option<u16>,
+    aspect: u8,
+}
+
+impl ExtendedAccessoryBuilder {
+    /// Sets the 11-bit output address. Returns `Error::InvalidAddress` if
+    /// the address is greater than `ExtendedAccessory::BROADCAST_ADDRESS`.
+    pub fn address(&mut self, address: u16) -> Result<&mut Self> {
+        if address > ExtendedAccessory::BROADCAST_ADDRESS {
+            Err(Error::InvalidAddress)
+        } else {
+            self.address = Some(address);
+            Ok(self)
+        }
+    }
+
+    /// Address the packet to all extended accessory decoders
+    pub fn broadcast(&mut self) -> &mut Self {
+        self.address = Some(ExtendedAccessory::BROADCAST_ADDRESS);
+        self
+    }
+
+    /// Sets the aspect number. Defaults to aspect 0 ("stop")
+    pub fn aspect(&mut self, aspect: u8) -> &mut Self {
+        self.aspect = aspect;
+        self
+    }
+
+    /// Build the `ExtendedAccessory` packet. Returns `Error::MissingField`
+    /// if the address has not been set
+    pub fn build(&mut self) -> Result<ExtendedAccessory> {
+        Ok(ExtendedAccessory {
+            address: self.address.ok_or(Error::MissingField)?,
+            aspect: self.aspect,
+        })
+    }
+}
+
+/// Accessory decoder addressed by an `AccessoryProgrammingOnMain` packet
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum AccessoryTarget {
+    /// Basic accessory decoder, by its 9-bit board address
+    Basic(u16),
+    /// Extended accessory decoder, by its 11-bit address
+    Extended(u16),
+}
+
+/// Operations-mode configuration variable access packet for accessory
+/// decoders: `10AAAAAA 1AAACDDD 1110CCVV VVVVVVVV DDDDDDDD`. This is the
+/// accessory-addressed form of `ProgrammingOnMain`, allowing accessory
+/// decoders to be configured in situ.
+///
+/// Basic accessory decoders are addressed with `CDDD = 0000`, meaning that
+/// the CV applies to the whole decoder rather than to a single output.
+/// Extended accessory decoders use the `10AAAAAA 0AAA0AA1` address form.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct AccessoryProgrammingOnMain {
+    target: AccessoryTarget,
+    typ: InstructionType,
+    cv_address: u16,
+}
+
+impl AccessoryProgrammingOnMain {
+    /// Create a builder for the `AccessoryProgrammingOnMain` packet
+    pub fn builder() -> AccessoryProgrammingOnMainBuilder {
+        AccessoryProgrammingOnMainBuilder::default()
+    }
+
+    /// Decode the bytes of an `AccessoryProgrammingOnMain` packet,
+    /// excluding the error detection byte
+    pub(super) fn parse(bytes: &[u8]) -> Option<Self> {
+        let [address, instr, cv_instr, cv_address, data] = *bytes else {
+            return None;
+        };
+        let target = if instr & 0x8f == 0x80 && address & 0xc0 == 0x80 {
+            AccessoryTarget::Basic(accessory_address(address, instr))
+        } else {
+            AccessoryTarget::Extended(ExtendedAccessory::read_address(
+                address, instr,
+            )?)
+        };
+        if cv_instr & 0xf0 != 0b1110_0000 {
+            return None;
+        }
+        let (typ, cv_address) =
+            InstructionType::decode([cv_instr, cv_address, data])?;
+        Some(Self {
+            target,
+            typ,
+            cv_address,
+        })
+    }
+
+    /// The accessory decoder being programmed
+    pub fn target(&self) -> AccessoryTarget {
+        self.target
+    }
+
+    /// The write or verify operation to perform on the CV
+    pub fn instruction_type(&self) -> InstructionType {
+        self.typ
+    }
+
+    /// The CV number, between 1 and 1024
+    pub fn cv_address(&self) -> u16 {
+        self.cv_address + 1
+    }
+}
+
+impl DccPacket for AccessoryProgrammingOnMain {
+    fn to_bytes(&self) -> RawPacket {
+        let [address, instr] = match self.target {
+            AccessoryTarget::Basic(address) => accessory_address_bytes(address),
+            AccessoryTarget::Extended(address) => {
+                extended_accessory_address_bytes(address)
+            }
+        };
+        let [mut cv_instr, cv_address, data] = self.typ.encode(self.cv_address);
+        cv_instr |= 0b1110_0000;
+
+        RawPacket::with_ecc(&[address, instr, cv_instr, cv_address, data])
+    }
+
+    fn repeats(&self) -> u8 {
+        // decoders only act on the second of two identical packets
+        2
+    }
+}
+
+/// Builder struct for `AccessoryProgrammingOnMain` packets
+#[derive(Default)]
+pub struct AccessoryProgrammingOnMainBuilder {
+    target: Option<AccessoryTarget>,
+    cv: CvAccess,
+}
+
+impl AccessoryProgrammingOnMainBuilder {
+    /// Address a basic accessory decoder by its 9-bit board address
+    /// (0-511). Returns `Error::InvalidAddress` if the address is out of
+    /// range.
+    pub fn board_address(&mut self, address: u16) -> Result<&mut Self> {
+        if address > BasicAccessory::MAX_BOARD_ADDRESS {
+            Err(Error::InvalidAddress)
+        } else {
+            self.target = Some(AccessoryTarget::Basic(address));
+            Ok(self)
+        }
+    }
+
+    /// Address an extended accessory decoder by its 11-bit address.
+    /// Returns `Error::InvalidAddress` if the address is greater than
+    /// `ExtendedAccessory::BROADCAST_ADDRESS`.
+    pub fn extended_address(&mut self, address: u16) -> Result<&mut Self> {
+        if address > ExtendedAccessory::BROADCAST_ADDRESS {
+            Err(Error::InvalidAddress)
+        } else {
+            self.target = Some(AccessoryTarget::Extended(address));
+            Ok(self)
+        }
+    }
+
+    /// Set the CV number, between 1 and 1024. Returns
+    /// `Error::InvalidAddress` if the CV address does not fit into 10 bits.
+    pub fn cv_address(&mut self, cv_address: u16) -> Result<&mut Self> {
+        self.cv.cv_address(cv_address)?;
+        Ok(self)
+    }
+
+    /// Create a `WriteCvByte` packet with the provided byte value
+    pub fn write_byte(&mut self, value: u8) -> &mut Self {
+        self.cv.write_byte(value);
+        self
+    }
+
+    /// Create a `VerifyCvByte` packet with the provided byte value
+    pub fn verify_byte(&mut self, value: u8) -> &mut Self {
+        self.cv.verify_byte(value);
+        self
+    }
+
+    /// Create a `WriteCvBit` packet with the provided bit offset and value
+    pub fn write_bit(&mut self, offset: u8, value: bool) -> Result<&mut Self> {
+        self.cv.write_bit(offset, value)?;
+        Ok(self)
+    }
+
+    /// Create a `VerifyCvBit` packet with the provided bit offset and value
+    pub fn verify_bit(&mut self, offset: u8, value: bool) -> Result<&mut Self> {
+        self.cv.verify_bit(offset, value)?;
+        Ok(self)
+    }
+
+    /// Validate that all fields are present and return an
+    /// `AccessoryProgrammingOnMain` packet
+    pub fn build(&mut self) -> Result<AccessoryProgrammingOnMain> {
+        let target = self.target.ok_or(Error::MissingField)?;
+        let (typ, cv_address) = self.cv.build()?;
+        Ok(AccessoryProgrammingOnMain {
+            target,
+            typ,
+            cv_address,
+        })
+    }
+}
+
+/// Instructions supported by the `DecoderControl` packet:
+/// * `Reset`: digital decoder reset, equivalent to the baseline `Reset`
+///   packet but addressed to a single decoder
+/// * `HardReset`: as `Reset`, but also clears the consist address (CV19)
+///   and restores CV29 to its default value
+/// * `FactoryTest`: reserved for use by manufacturers. The `F` bit is sent
+///   clear, but either value is accepted when decoding.
+/// * `SetAdvancedAddressing`: sets or clears bit 5 of CV29, switching the
+///   decoder between short and long address mode
+/// * `AcknowledgementRequest`: ask the decoder to respond with an
+///   acknowledgement
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[allow(missing_docs)]
+pub enum DecoderControlInstruction {
+    Reset,
+    HardReset,
+    FactoryTest,
+    SetAdvancedAddressing(bool),
+    AcknowledgementRequest,
+}
+
+/// Decoder Control packet: `0000 CCCF`. Used to reset or reconfigure a
+/// single multi-function decoder without affecting the rest of the layout.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct DecoderControl {
+    address: Address,
+    instruction: DecoderControlInstruction,
+}
+
+impl DecoderControl {
+    /// Create a `DecoderControl` packet sending the given instruction to
+    /// the decoder at `address`
+    pub fn new(
+        address: Address,
+        instruction: DecoderControlInstruction,
+    ) -> Self {
+        Self {
+            address,
+            instruction,
+        }
+    }
+
+    /// Decode a `0000 CCCF` decoder control instruction sent to `address`
+    pub(super) fn parse(address: Address, instruction: &[u8]) -> Option<Self> {
+        let instruction = match *instruction {
+            [0b0000_0000] => DecoderControlInstruction::Reset,
+            [0b0000_0001] => DecoderControlInstruction::HardReset,
+            [0b0000_0010 | 0b0000_0011] => {
+                DecoderControlInstruction::FactoryTest
+            }
+            [instr @ 0b0000_1010..=0b0000_1011] => {
+                DecoderControlInstruction::SetAdvancedAddressing(
+                    instr & 0x01 != 0,
+                )
+            }
+            [0b0000_1111] => DecoderControlInstruction::AcknowledgementRequest,
+            _ => return None,
+        };
+        Some(Self::new(address, instruction))
+    }
+
+    /// The address of the loco
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// The instruction sent to the decoder
+    pub fn instruction(&self) -> DecoderControlInstruction {
+        self.instruction
+    }
+}
+
+impl DccPacket for DecoderControl {
+    fn to_bytes(&self) -> RawPacket {
+        #[allow(clippy::unusual_byte_groupings)]
+        let instr = match self.instruction {
+            DecoderControlInstruction::Reset => 0b0000_000_0,
+            DecoderControlInstruction::HardReset => 0b0000_000_1,
+            DecoderControlInstruction::FactoryTest => 0b0000_001_0,
+            DecoderControlInstruction::SetAdvancedAddressing(enable) => {
+                0b0000_101_0 | enable as u8
+            }
+            DecoderControlInstruction::AcknowledgementRequest => 0b0000_111_1,
+        };
+        super::addressed_bytes(self.address, &[instr])
+    }
+
+    fn repeats(&self) -> u8 {
+        // decoders only act on the second of two identical packets
+        2
+    }
+}
+
+/// Direction of a loco within a consist, relative to the consist as a
+/// whole
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum ConsistDirection {
+    /// Loco runs in the same direction as the consist
+    #[default]
+    Normal,
+    /// Loco faces backwards, so runs in the opposite direction to the
+    /// consist
+    Reversed,
+}
+
+/// Consist Control packet: `0001 001D 0AAAAAAA`. Sets or clears a loco's
+/// advanced consist address (CV19). Once set, the loco responds to speed
+/// and direction packets sent to the consist address instead of its own
+/// address.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct ConsistControl {
+    address: Address,
+    consist_address: u8,
+    direction: ConsistDirection,
+}
+
+impl ConsistControl {
+    /// Add the loco at `address` to the consist at `consist_address`, which
+    /// must be in the range 1-127. Returns `Error::InvalidAddress` if the
+    /// consist address is out of range.
+    pub fn set(
+        address: Address,
+        consist_address: u8,
+        direction: ConsistDirection,
+    ) -> Result<Self> {
+        if consist_address == 0 || consist_address > 0x7f {
+            Err(Error::InvalidAddress)
+        } else {
+            Ok(Self {
+                address,
+                consist_address,
+                direction,
+            })
+        }
+    }
+
+    /// Remove the loco at `address` from its consist by clearing its
+    /// consist address
+    pub fn clear(address: Address) -> Self {
+        Self {
+            address,
+            consist_address: 0,
+            direction: ConsistDirection::Normal,
+        }
+    }
+
+    /// Decode a `0001 001D` consist control instruction sent to `address`
+    pub(super) fn parse(address: Address, instruction: &[u8]) -> Option<Self> {
+        match *instruction {
+            [instr @ 0b0001_0010..=0b0001_0011, consist_address @ 0..=0x7f] => {
+                Some(Self {
+                    address,
+                    consist_address,
+                    direction: if instr & 0x01 != 0 {
+                        ConsistDirection::Reversed
+                    } else {
+                        ConsistDirection::Normal
+                    },
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// The address of the loco
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// The consist address, or 0 if the loco is being removed from its
+    /// consist
+    pub fn consist_address(&self) -> u8 {
+        self.consist_address
+    }
+
+    /// The direction of the loco relative to the consist
+    pub fn direction(&self) -> ConsistDirection {
+        self.direction
+    }
+}
+
+impl DccPacket for ConsistControl {
+    fn to_bytes(&self) -> RawPacket {
+        let mut instr = 0b0001_0010;
+        if let ConsistDirection::Reversed = self.direction {
+            instr |= 0x01;
+        }
+        super::addressed_bytes(self.address, &[instr, self.consist_address])
+    }
+
+    fn repeats(&self) -> u8 {
+        // decoders only act on the second of two identical packets
+        2
+    }
+}
+
+/// A loco which is part of a `Consist`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct ConsistMember {
+    /// The loco's own address
+    pub address: Address,
+    /// Direction of the loco relative to the consist
+    pub direction: ConsistDirection,
+}
+
+/// A multiple-unit train made up of a lead loco and up to `N` trailing
+/// locos, all of which respond to a shared short consist address.
+///
+/// `Consist` does not send anything by itself; it keeps track of the
+/// members so that a throttle can generate the `ConsistControl` packets
+/// needed to build or break the consist.
+pub struct Consist<const N: usize> {
+    address: u8,
+    lead: ConsistMember,
+    trailing: [Option<ConsistMember>; N],
+}
+
+impl<const N: usize> Consist<N> {
+    /// Create a consist at `address` (1-127) with the given lead loco.
+    /// Returns `Error::InvalidAddress` if the consist address is out of
+    /// range.
+    pub fn new(
+        address: u8,
+        lead: Address,
+        direction: ConsistDirection,
+    ) -> Result<Self> {
+        if address == 0 || address > 0x7f {
+            return Err(Error::InvalidAddress);
+        }
+        Ok(Self {
+            address,
+            lead: ConsistMember {
+                address: lead,
+                direction,
+            },
+            trailing: [None; N],
+        })
+    }
+
+    /// The consist address, to which speed and direction packets for the
+    /// whole consist should be sent
+    pub fn address(&self) -> Address {
+        Address::Short(self.address)
+    }
+
+    /// The lead loco
+    pub fn lead(&self) -> ConsistMember {
+        self.lead
+    }
+
+    /// Iterate over the trailing locos
+    pub fn trailing(&self) -> impl Iterator<Item = ConsistMember> + '_ {
+        self.trailing.iter().flatten().copied()
+    }
+
+    /// Iterate over every loco in the consist, starting with the lead
+    pub fn members(&self) -> impl Iterator<Item = ConsistMember> + '_ {
+        core::iter::once(self.lead).chain(self.trailing())
+    }
+
+    /// Add a trailing loco to the consist, returning the `ConsistControl`
+    /// packet which adds it. Returns `Error::Full` if there is no room for
+    /// another loco, or `Error::InvalidAddress` if the loco is already part
+    /// of the consist.
+    pub fn add(
+        &mut self,
+        address: Address,
+        direction: ConsistDirection,
+    ) -> Result<ConsistControl> {
+        if self.members().any(|member| member.address == address) {
+            return Err(Error::InvalidAddress);
+        }
+        let slot = self
+            .trailing
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(Error::Full)?;
+        *slot = Some(ConsistMember { address, direction });
+        ConsistControl::set(address, self.address, direction)
+    }
+
+    /// Remove a trailing loco from the consist, returning the
+    /// `ConsistControl` packet which clears its consist address, or `None`
+    /// if the loco is not a trailing member of this consist
+    pub fn remove(&mut self, address: Address) -> Option<ConsistControl> {
+        let slot = self.trailing.iter_mut().find(
+            |slot| matches!(slot, Some(member) if member.address == address),
+        )?;
+        *slot = None;
+        Some(ConsistControl::clear(address))
+    }
+
+    /// Packets which assign the consist address to every member
+    pub fn build_packets(&self) -> impl Iterator<Item = ConsistControl> + '_ {
+        let address = self.address;
+        self.members().map(move |member| ConsistControl {
+            address: member.address,
+            consist_address: address,
+            direction: member.direction,
+        })
+    }
+
+    /// Packets which clear the consist address of every member, breaking
+    /// up the consist
+    pub fn break_packets(&self) -> impl Iterator<Item = ConsistControl> + '_ {
+        self.members()
+            .map(|member| ConsistControl::clear(member.address))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packets::test::serialise_bytes;
+    use crate::packets::SerialiseBuffer;
+    use bitvec::prelude::*;
+
+    #[test]
+    fn validate_addresses() {
+        assert_eq!(Address::short(0), Err(Error::InvalidAddress));
+        assert_eq!(Address::short(3), Ok(Address::Short(3)));
+        assert_eq!(Address::short(127), Ok(Address::Short(127)));
+        assert_eq!(Address::short(128), Err(Error::InvalidAddress));
+
+        assert_eq!(Address::long(0), Ok(Address::Long(0)));
+        assert_eq!(Address::long(10239), Ok(Address::Long(10239)));
+        assert_eq!(Address::long(10240), Err(Error::InvalidAddress));
+    }
+
+    #[test]
+    fn address_validity() {
+        assert!(Address::Short(1).is_valid());
+        assert!(!Address::Short(0).is_valid());
+        assert!(!Address::Short(200).is_valid());
+        assert!(Address::Long(10239).is_valid());
+        assert!(!Address::Long(60000).is_valid());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn invalid_address_is_not_written() {
+        let mut buf = [0; 2];
+        Address::Long(60000).write_bytes(&mut buf);
+    }
+
+    #[test]
+    fn long_address_bytes() {
+        let mut buf = [0; 2];
+        let len = Address::long(1234).unwrap().write_bytes(&mut buf);
+        assert_eq!(len, 2);
+        // 1234 = 0x04d2
+        assert_eq!(buf, [0b1100_0100, 0xd2]);
+
+        let len = Address::long(10239).unwrap().write_bytes(&mut buf);
+        assert_eq!(len, 2);
+        assert_eq!(buf, [0b1110_0111, 0xff]);
+    }
+
+    #[test]
+    fn serialise_speed_and_direction_128() -> Result<()> {
+        let pkt = SpeedAndDirection128::builder()
+            .address(3)?
+            .speed(100)?
+            .direction(Direction::Forward)
+            .build();
+        let mut buf = SerialiseBuffer::default();
+        let len = pkt.serialise(&mut buf)?;
+
+        // [    preamble    ] S 0000 0011 S 0011 1111 S 1110 0101 S 1101 1001 S
+        #[allow(clippy::unusual_byte_groupings)]
+        let expected_arr = [
+            0b1111_1111_u8, // PPPP PPPP
+            0b1111_111_0,   // PPPP PPPS
+            0b0000_0011,    // AAAA AAAA
+            0b0_0011_111,   // SIII IIII
+            0b1_0_111001,   // ISDS SSSS
+            0b01_0_11011,   // SSSE EEEE
+            0b001_1_0000,   // EEES ----
+        ];
+        let mut expected = SerialiseBuffer::default();
+        expected[..52]
+            .copy_from_bitslice(&expected_arr.view_bits::<Msb0>()[..52]);
+        assert_eq!(len, 52);
+        assert_eq!(buf[..len], expected[..52]);
+        Ok(())
+    }
+
+    #[test]
+    fn serialise_long_address_e_stop_128() -> Result<()> {
+        let pkt = SpeedAndDirection128::builder()
+            .long_address(4000)?
+            .speed(50)?
+            .e_stop(true)
+            .direction(Direction::Backward)
+            .build();
+        let mut buf = SerialiseBuffer::default();
+        let len = pkt.serialise(&mut buf)?;
+
+        // 4000 = 0x0fa0
+        let (expected, expected_len) = serialise_bytes(&[
+            0xcf,
+            0xa0,
+            0x3f,
+            0x01,
+            0xcf ^ 0xa0 ^ 0x3f ^ 0x01,
+        ]);
+        assert_eq!(len, 61);
+        assert_eq!(buf[..len], expected[..expected_len]);
+        Ok(())
+    }
+
+    #[test]
+    fn speed_128_range() {
+        let mut builder = SpeedAndDirection128::builder();
+        assert!(builder.speed(126).is_ok());
+        assert_eq!(builder.speed(127).err(), Some(Error::InvalidSpeed));
+        let pkt = builder.speed(126).unwrap().build();
+        assert_eq!(pkt.speed, 0xff);
+    }
+
+    #[test]
+    fn set_functions() {
+        let mut functions = Functions::new();
+        functions.set(0, true).unwrap().set(12, true).unwrap();
+        assert!(functions.get(0));
+        assert!(!functions.get(1));
+        assert!(functions.get(12));
+        assert_eq!(functions.set(69, true).err(), Some(Error::InvalidFunction));
+        functions.set(0, false).unwrap();
+        assert!(!functions.get(0));
+    }
+
+    #[test]
+    fn serialise_function_group_one() -> Result<()> {
+        let mut functions = Functions::new();
+        functions.set(0, true)?.set(2, true)?.set(5, true)?;
+        let pkt = FunctionControl::builder()
+            .address(3)?
+            .group(FunctionGroup::F0ToF4)
+            .functions(functions)
+            .build()?;
+        let mut buf = SerialiseBuffer::default();
+        let len = pkt.serialise(&mut buf)?;
+
+        // [    preamble    ] S 0000 0011 S 1001 0010 S 1001 0001 S
+        #[allow(clippy::unusual_byte_groupings)]
+        let expected_arr = [
+            0b1111_1111_u8, // PPPP PPPP
+            0b1111_111_0,   // PPPP PPPS
+            0b0000_0011,    // AAAA AAAA
+            0b0_100_1_001,  // S100 LFFF
+            0b0_0_100100,   // FSEE EEEE
+            0b01_1_00000,   // EES- ----
+        ];
+        let mut expected = SerialiseBuffer::default();
+        expected[..43]
+            .copy_from_bitslice(&expected_arr.view_bits::<Msb0>()[..43]);
+        assert_eq!(len, 43);
+        assert_eq!(buf[..len], expected[..43]);
+        Ok(())
+    }
+
+    #[test]
+    fn serialise_function_group_two() -> Result<()> {
+        let mut functions = Functions::new();
+        functions.set(5, true)?.set(8, true)?.set(10, true)?;
+        let mut builder = FunctionControl::builder();
+        builder.long_address(1234)?.functions(functions);
+        let mut buf = SerialiseBuffer::default();
+
+        let len = builder
+            .group(FunctionGroup::F5ToF8)
+            .build()?
+            .serialise(&mut buf)?;
+        let (expected, _) = serialise_bytes(&[
+            0xc4,
+            0xd2,
+            0b1011_1001,
+            0xc4 ^ 0xd2 ^ 0b1011_1001,
+        ]);
+        assert_eq!(buf[..len], expected[..len]);
+
+        let len = builder
+            .group(FunctionGroup::F9ToF12)
+            .build()?
+            .serialise(&mut buf)?;
+        let (expected, _) = serialise_bytes(&[
+            0xc4,
+            0xd2,
+            0b1010_0010,
+            0xc4 ^ 0xd2 ^ 0b1010_0010,
+        ]);
+        assert_eq!(buf[..len], expected[..len]);
+        Ok(())
+    }
+
+    #[test]
+    fn function_control_requires_group() {
+        let mut builder = FunctionControl::builder();
+        builder.address(3).unwrap();
+        assert_eq!(builder.build().err(), Some(Error::MissingField));
+    }
+
+    #[test]
+    fn serialise_feature_expansion() -> Result<()> {
+        let mut functions = Functions::new();
+        functions.set(13, true)?.set(20, true)?.set(68, true)?;
+        let mut builder = FunctionControl::builder();
+        builder.address(3)?.functions(functions);
+        let mut buf = SerialiseBuffer::default();
+
+        let len = builder
+            .group(FunctionGroup::F13ToF20)
+            .build()?
+            .serialise(&mut buf)?;
+        assert_eq!(len, 52);
+        let (expected, _) = serialise_bytes(&[
+            0x03,
+            0b1101_1110,
+            0b1000_0001,
+            0x03 ^ 0b1101_1110 ^ 0b1000_0001,
+        ]);
+        assert_eq!(buf[..len], expected[..len]);
+
+        let len = builder
+            .group(FunctionGroup::F61ToF68)
+            .build()?
+            .serialise(&mut buf)?;
+        let (expected, _) = serialise_bytes(&[
+            0x03,
+            0b1101_1100,
+            0b1000_0000,
+            0x03 ^ 0b1101_1100 ^ 0b1000_0000,
+        ]);
+        assert_eq!(buf[..len], expected[..len]);
+        Ok(())
+    }
+
+    #[test]
+    fn any_function_in_group() -> Result<()> {
+        let mut functions = Functions::new();
+        functions.set(21, true)?;
+        assert!(!functions.any(FunctionGroup::F13ToF20));
+        assert!(functions.any(FunctionGroup::F21ToF28));
+        Ok(())
+    }
+
+    #[test]
+    fn changed_function_groups() -> Result<()> {
+        let previous = Functions::new();
+        let mut functions = previous;
+        assert_eq!(functions.changed_groups(&previous).count(), 0);
+
+        functions
+            .set(0, true)?
+            .set(4, true)?
+            .set(29, true)?
+            .set(68, true)?;
+        let mut changed = functions.changed_groups(&previous);
+        assert_eq!(changed.next(), Some(FunctionGroup::F0ToF4));
+        assert_eq!(changed.next(), Some(FunctionGroup::F29ToF36));
+        assert_eq!(changed.next(), Some(FunctionGroup::F61ToF68));
+        assert_eq!(changed.next(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn serialise_programming_on_main_write_byte() -> Result<()> {
+        // CV 3 (acceleration) is sent as address 2
+        let pkt = ProgrammingOnMain::builder()
+            .address(3)?
+            .cv_address(3)?
+            .write_byte(25)
+            .build()?;
+        let mut buf = SerialiseBuffer::default();
+        let len = pkt.serialise(&mut buf)?;
+
+        // [    preamble    ] S 0000 0011 S 1110 1100 S 0000 0010
+        // S 0001 1001 S 1111 0100 S
+        #[allow(clippy::unusual_byte_groupings)]
+        let expected_arr = [
+            0b1111_1111_u8, // PPPP PPPP
+            0b1111_111_0,   // PPPP PPPS
+            0b0000_0011,    // AAAA AAAA
+            0b0_1110_11_0,  // S111 0CCV
+            0b0_0_000000,   // VSVV VVVV
+            0b10_0_00011,   // VVSD DDDD
+            0b001_0_1111,   // DDDS EEEE
+            0b0100_1_000,   // EEEE S---
+        ];
+        let mut expected = SerialiseBuffer::default();
+        expected[..61]
+            .copy_from_bitslice(&expected_arr.view_bits::<Msb0>()[..61]);
+        assert_eq!(len, 61);
+        assert_eq!(buf[..len], expected[..61]);
+        Ok(())
+    }
+
+    #[test]
+    fn serialise_programming_on_main_long_address_bit() -> Result<()> {
+        // write 1 into bit 5 of CV 29, which is the six-byte worst case
+        let pkt = ProgrammingOnMain::builder()
+            .long_address(1234)?
+            .cv_address(29)?
+            .write_bit(5, true)?
+            .build()?;
+        let mut buf = SerialiseBuffer::default();
+        let len = pkt.serialise(&mut buf)?;
+
+        let (expected, expected_len) = serialise_bytes(&[
+            0xc4,
+            0xd2,
+            0b1110_1000,
+            28,
+            0b1111_1101,
+            0xc4 ^ 0xd2 ^ 0b1110_1000 ^ 28 ^ 0b1111_1101,
+        ]);
+        assert_eq!(len, 70);
+        assert_eq!(buf[..len], expected[..expected_len]);
+        Ok(())
+    }
+
+    #[test]
+    fn programming_on_main_requires_fields() {
+        let mut builder = ProgrammingOnMain::builder();
+        builder.address(3).unwrap().cv_address(1).unwrap();
+        assert_eq!(builder.build().err(), Some(Error::MissingField));
+        assert_eq!(builder.cv_address(1025).err(), Some(Error::InvalidAddress));
+    }
+
+    #[test]
+    fn serialise_basic_accessory() -> Result<()> {
+        let pkt = BasicAccessory::builder()
+            .turnout(1)?
+            .direction(TurnoutDirection::Thrown)
+            .build()?;
+        let mut buf = SerialiseBuffer::default();
+        let len = pkt.serialise(&mut buf)?;
+
+        // [    preamble    ] S 1000 0001 S 1111 1000 S 0111 1001 S
+        #[allow(clippy::unusual_byte_groupings)]
+        let expected_arr = [
+            0b1111_1111_u8, // PPPP PPPP
+            0b1111_111_0,   // PPPP PPPS
+            0b10_000001,    // 10AA AAAA
+            0b0_1_111_1_00, // S1AA ACDD
+            0b0_0_011110,   // DSEE EEEE
+            0b01_1_00000,   // EES- ----
+        ];
+        let mut expected = SerialiseBuffer::default();
+        expected[..43]
+            .copy_from_bitslice(&expected_arr.view_bits::<Msb0>()[..43]);
+        assert_eq!(len, 43);
+        assert_eq!(buf[..len], expected[..43]);
+        Ok(())
+    }
+
+    #[test]
+    fn basic_accessory_addressing() -> Result<()> {
+        let mut buf = SerialiseBuffer::default();
+
+        // the last turnout is the last pair on the board below broadcast
+        let len = BasicAccessory::builder()
+            .turnout(2040)?
+            .build()?
+            .serialise(&mut buf)?;
+        let (expected, _) = serialise_bytes(&[0xbe, 0x8f, 0xbe ^ 0x8f]);
+        assert_eq!(buf[..len], expected[..len]);
+
+        let len = BasicAccessory::builder()
+            .broadcast(3)?
+            .build()?
+            .serialise(&mut buf)?;
+        let (expected, _) = serialise_bytes(&[0xbf, 0x8f, 0xbf ^ 0x8f]);
+        assert_eq!(buf[..len], expected[..len]);
+
+        // board 65 = 0b001_000001, deactivated
+        let len = BasicAccessory::builder()
+            .board_address(65, 2)?
+            .activate(false)
+            .build()?
+            .serialise(&mut buf)?;
+        let (expected, _) = serialise_bytes(&[0x81, 0xe5, 0x81 ^ 0xe5]);
+        assert_eq!(buf[..len], expected[..len]);
+
+        let mut builder = BasicAccessory::builder();
+        assert_eq!(builder.turnout(0).err(), Some(Error::InvalidAddress));
+        assert_eq!(builder.turnout(2041).err(), Some(Error::InvalidAddress));
+        assert_eq!(builder.broadcast(4).err(), Some(Error::InvalidAddress));
+        assert_eq!(
+            builder.board_address(512, 0).err(),
+            Some(Error::InvalidAddress)
+        );
+        assert_eq!(
+            builder.board_address(1, 4).err(),
+            Some(Error::InvalidAddress)
+        );
+        assert_eq!(builder.build().err(), Some(Error::MissingField));
+        Ok(())
+    }
+
+    #[test]
+    fn serialise_extended_accessory() -> Result<()> {
+        // address 0b010_000011_10 = 526
+        let pkt = ExtendedAccessory::builder()
+            .address(526)?
+            .aspect(0x15)
+            .build()?;
+        let mut buf = SerialiseBuffer::default();
+        let len = pkt.serialise(&mut buf)?;
+
+        // [    preamble    ] S 1000 0011 S 0101 0101 S 0001 0101 S
+        // 1100 0011 S
+        #[allow(clippy::unusual_byte_groupings)]
+        let expected_arr = [
+            0b1111_1111_u8, // PPPP PPPP
+            0b1111_111_0,   // PPPP PPPS
+            0b10_000011,    // 10AA AAAA
+            0b0_0_101_0_10, // S0AA A0AA
+            0b1_0_000101,   // 1SXX XXXX
+            0b01_0_11000,   // XXSE EEEE
+            0b011_1_0000,   // EEES ----
+        ];
+        let mut expected = SerialiseBuffer::default();
+        expected[..52]
+            .copy_from_bitslice(&expected_arr.view_bits::<Msb0>()[..52]);
+        assert_eq!(len, 52);
+        assert_eq!(buf[..len], expected[..52]);
+        Ok(())
+    }
+
+    #[test]
+    fn serialise_extended_accessory_broadcast() -> Result<()> {
+        let pkt = ExtendedAccessory::builder().broadcast().build()?;
+        let mut buf = SerialiseBuffer::default();
+        let len = pkt.serialise(&mut buf)?;
+        let (expected, _) =
+            serialise_bytes(&[0b1011_1111, 0b0000_0111, 0x00, 0b1011_1000]);
+        assert_eq!(buf[..len], expected[..len]);
+
+        assert_eq!(
+            ExtendedAccessory::builder().address(0x0800).err(),
+            Some(Error::InvalidAddress)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn serialise_accessory_programming_on_main() -> Result<()> {
+        // write 0x55 into CV 513 of basic accessory board 1
+        let pkt = AccessoryProgrammingOnMain::builder()
+            .board_address(1)?
+            .cv_address(513)?
+            .write_byte(0x55)
+            .build()?;
+        let mut buf = SerialiseBuffer::default();
+        let len = pkt.serialise(&mut buf)?;
+
+        // [    preamble    ] S 1000 0001 S 1111 0000 S 1110 1110 S
+        // 0000 0000 S 0101 0101 S 1100 1010 S
+        #[allow(clippy::unusual_byte_groupings)]
+        let expected_arr = [
+            0b1111_1111_u8, // PPPP PPPP
+            0b1111_111_0,   // PPPP PPPS
+            0b10_000001,    // 10AA AAAA
+            0b0_1_111_0_00, // S1AA ACDD
+            0b0_0_1110_11,  // DS11 10CC
+            0b10_0_00000,   // VVSV VVVV
+            0b000_0_0101,   // VVVS DDDD
+            0b0101_0_110,   // DDDD SEEE
+            0b01010_1_00,   // EEEE ES--
+        ];
+        let mut expected = SerialiseBuffer::default();
+        expected[..70]
+            .copy_from_bitslice(&expected_arr.view_bits::<Msb0>()[..70]);
+        assert_eq!(len, 70);
+        assert_eq!(buf[..len], expected[..70]);
+        Ok(())
+    }
+
+    #[test]
+    fn serialise_extended_accessory_programming_on_main() -> Result<()> {
+        let pkt = AccessoryProgrammingOnMain::builder()
+            .extended_address(526)?
+            .cv_address(1)?
+            .verify_bit(0, true)?
+            .build()?;
+        let mut buf = SerialiseBuffer::default();
+        let len = pkt.serialise(&mut buf)?;
+        let (expected, expected_len) = serialise_bytes(&[
+            0x83,
+            0x55,
+            0b1110_1000,
+            0x00,
+            0b1110_1000,
+            0x83 ^ 0x55,
+        ]);
+        assert_eq!(len, 70);
+        assert_eq!(buf[..len], expected[..expected_len]);
+        Ok(())
+    }
+
+    #[test]
+    fn serialise_consist_control() -> Result<()> {
+        let pkt = ConsistControl::set(
+            Address::short(3)?,
+            10,
+            ConsistDirection::Reversed,
+        )?;
+        let mut buf = SerialiseBuffer::default();
+        let len = pkt.serialise(&mut buf)?;
+
+        // [    preamble    ] S 0000 0011 S 0001 0011 S 0000 1010 S
+        // 0001 1010 S
+        #[allow(clippy::unusual_byte_groupings)]
+        let expected_arr = [
+            0b1111_1111_u8, // PPPP PPPP
+            0b1111_111_0,   // PPPP PPPS
+            0b0000_0011,    // AAAA AAAA
+            0b0_0001_001,   // S000 1001
+            0b1_0_000010,   // DS0C CCCC
+            0b10_0_00011,   // CCSE EEEE
+            0b010_1_0000,   // EEES ----
+        ];
+        let mut expected = SerialiseBuffer::default();
+        expected[..52]
+            .copy_from_bitslice(&expected_arr.view_bits::<Msb0>()[..52]);
+        assert_eq!(len, 52);
+        assert_eq!(buf[..len], expected[..52]);
+
+        let len =
+            ConsistControl::clear(Address::long(1234)?).serialise(&mut buf)?;
+        let (expected, _) =
+            serialise_bytes(&[0xc4, 0xd2, 0x12, 0x00, 0xc4 ^ 0xd2 ^ 0x12]);
+        assert_eq!(buf[..len], expected[..len]);
+
+        assert!(ConsistControl::set(
+            Address::short(3)?,
+            0,
+            ConsistDirection::Normal
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn build_and_break_consist() -> Result<()> {
+        let lead = Address::short(3)?;
+        let trailing = Address::long(1234)?;
+        let mut consist: Consist<2> =
+            Consist::new(10, lead, ConsistDirection::Normal)?;
+        assert_eq!(consist.address(), Address::Short(10));
+
+        consist.add(trailing, ConsistDirection::Reversed)?;
+        assert_eq!(
+            consist.add(trailing, ConsistDirection::Normal).err(),
+            Some(Error::InvalidAddress)
+        );
+        consist.add(Address::short(4)?, ConsistDirection::Normal)?;
+        assert_eq!(
+            consist
+                .add(Address::short(5)?, ConsistDirection::Normal)
+                .err(),
+            Some(Error::Full)
+        );
+        assert_eq!(consist.members().count(), 3);
+
+        let built: Vec<_> = consist.build_packets().collect();
+        assert_eq!(built.len(), 3);
+        assert_eq!(built[0].address, lead);
+        assert_eq!(built[1].address, trailing);
+        assert_eq!(built[1].consist_address, 10);
+        assert_eq!(built[1].direction, ConsistDirection::Reversed);
+
+        let removed = consist.remove(trailing).unwrap();
+        assert_eq!(removed.consist_address, 0);
+        assert!(consist.remove(trailing).is_none());
+        assert!(consist.remove(lead).is_none());
+
+        assert!(consist.break_packets().all(|pkt| pkt.consist_address == 0));
+        assert_eq!(consist.break_packets().count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn serialise_decoder_control() -> Result<()> {
+        let pkt = DecoderControl::new(
+            Address::short(3)?,
+            DecoderControlInstruction::HardReset,
+        );
+        let mut buf = SerialiseBuffer::default();
+        let len = pkt.serialise(&mut buf)?;
+
+        // [    preamble    ] S 0000 0011 S 0000 0001 S 0000 0010 S
+        #[allow(clippy::unusual_byte_groupings)]
+        let expected_arr = [
+            0b1111_1111_u8, // PPPP PPPP
+            0b1111_111_0,   // PPPP PPPS
+            0b0000_0011,    // AAAA AAAA
+            0b0_0000_000,   // S000 0CCC
+            0b1_0_000000,   // FSEE EEEE
+            0b10_1_00000,   // EES- ----
+        ];
+        let mut expected = SerialiseBuffer::default();
+        expected[..43]
+            .copy_from_bitslice(&expected_arr.view_bits::<Msb0>()[..43]);
+        assert_eq!(len, 43);
+        assert_eq!(buf[..len], expected[..43]);
+        Ok(())
+    }
+
+    #[test]
+    fn decoder_control_instructions() -> Result<()> {
+        let address = Address::long(1234)?;
+        let mut buf = SerialiseBuffer::default();
+        for (instruction, byte) in [
+            (DecoderControlInstruction::Reset, 0x00),
+            (DecoderControlInstruction::FactoryTest, 0x02),
+            (DecoderControlInstruction::SetAdvancedAddressing(true), 0x0b),
+            (
+                DecoderControlInstruction::SetAdvancedAddressing(false),
+                0x0a,
+            ),
+            (DecoderControlInstruction::AcknowledgementRequest, 0x0f),
+        ] {
+            let len = DecoderControl::new(address, instruction)
+                .serialise(&mut buf)?;
+            let (expected, _) =
+                serialise_bytes(&[0xc4, 0xd2, byte, 0xc4 ^ 0xd2 ^ byte]);
+            assert_eq!(buf[..len], expected[..len]);
+        }
+        Ok(())
+    }
+}
@@ -6,27 +6,39 @@
 
 pub mod baseline;
 pub mod extended;
+pub mod packet;
 pub mod service_mode;
 
 pub use baseline::*;
 pub use extended::*;
+pub use packet::*;
 pub use service_mode::*;
 
-use crate::Error;
+use crate::{config, Error};
 use bitvec::prelude::*;
 
 /// Convenient Result wrapper
 pub type Result<T> = core::result::Result<T, Error>;
 
-struct Preamble(BitArr!(for 14, in u8, Msb0));
-
-const MAX_BITS: usize = 15 + 4 * 9 + 1;
+/// Maximum number of data bytes (including the error detection byte) in
+/// a packet. The longest packets defined by the standard are six bytes:
+/// configuration variable access on the main, either to a long-address
+/// loco or to an accessory decoder.
+pub(crate) const MAX_BYTES: usize = 6;
+const MAX_BITS: usize = 15 + MAX_BYTES * 9 + 1;
 /// Buffer long enough to serialise any common DCC packet into
 pub type SerialiseBuffer = BitArr!(for MAX_BITS, in u8, Msb0);
 
 /// Method used to perform serialisations. Should be less error-prone
 /// than all of the manual bit offsets we implemented in baseline.
-fn serialise(data: &[u8], buf: &mut SerialiseBuffer) -> Result<usize> {
+///
+/// Packets are always serialised with a 15 bit preamble; the
+/// `DccInterruptHandler` adjusts this to the configured length when the
+/// packet is sent.
+pub(crate) fn serialise(
+    data: &[u8],
+    buf: &mut SerialiseBuffer,
+) -> Result<usize> {
     // check that the provided data will fit into the buffer
     let required_bits = 15 + data.len() * 9 + 1;
     if required_bits > MAX_BITS {
@@ -49,9 +61,133 @@ fn serialise(data: &[u8], buf: &mut SerialiseBuffer) -> Result<usize> {
     Ok(pos)
 }
 
+/// Common interface of every packet type, allowing packets to be handled
+/// generically, e.g. by `DccInterruptHandler::write_packet`
+pub trait DccPacket {
+    /// The bytes of the packet, including the error detection byte
+    fn to_bytes(&self) -> RawPacket;
+
+    /// Serialise the packet into the provided buffer with a 15 bit
+    /// preamble. Returns the number of bits written or an `Error::TooLong`
+    /// if the buffer has insufficient capacity
+    fn serialise(&self, buf: &mut SerialiseBuffer) -> Result<usize> {
+        serialise(self.to_bytes().bytes(), buf)
+    }
+
+    /// Whether this is a service-mode packet, which is only sent on the
+    /// programming track
+    fn is_service_mode(&self) -> bool {
+        false
+    }
+
+    /// Preamble length which the packet should be sent with. Service-mode
+    /// packets need a longer preamble than operations-mode packets.
+    fn preamble_bits(&self) -> usize {
+        if self.is_service_mode() {
+            config::SERVICE_MODE_PREAMBLE
+        } else {
+            config::PREAMBLE
+        }
+    }
+
+    /// Number of times the packet should be sent in a row for decoders to
+    /// act on it
+    fn repeats(&self) -> u8 {
+        1
+    }
+}
+
+/// The bytes of a packet, including the error detection byte
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct RawPacket {
+    bytes: [u8; MAX_BYTES],
+    len: usize,
+}
+
+impl RawPacket {
+    /// Create a packet from its bytes, including the error detection byte.
+    /// Returns `Error::TooLong` if there are more bytes than fit into a
+    /// packet.
+    pub fn new(bytes: &[u8]) -> Result<Self> {
+        let mut raw = Self::default();
+        raw.bytes
+            .get_mut(..bytes.len())
+            .ok_or(Error::TooLong)?
+            .copy_from_slice(bytes);
+        raw.len = bytes.len();
+        Ok(raw)
+    }
+
+    /// Create a packet from its data bytes, appending the error detection
+    /// byte, which is the XOR of all of the data bytes. There must be
+    /// fewer than `MAX_BYTES` data bytes.
+    fn with_ecc(data: &[u8]) -> Self {
+        let mut raw = Self::default();
+        raw.bytes[..data.len()].copy_from_slice(data);
+        raw.bytes[data.len()] = data.iter().fold(0, |ecc, byte| ecc ^ byte);
+        raw.len = data.len() + 1;
+        raw
+    }
+
+    /// All of the bytes in the packet, including the error detection byte
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// The bytes in the packet, excluding the error detection byte
+    pub fn data(&self) -> &[u8] {
+        &self.bytes[..self.len.saturating_sub(1)]
+    }
+}
+
+impl DccPacket for RawPacket {
+    fn to_bytes(&self) -> RawPacket {
+        *self
+    }
+}
+
+/// Build a packet addressed to a multi-function decoder. The address
+/// bytes are followed by the instruction bytes and then by the error
+/// detection byte.
+fn addressed_bytes(address: Address, instruction: &[u8]) -> RawPacket {
+    let mut data = [0; MAX_BYTES];
+    let mut len = address.write_bytes(&mut data);
+    data[len..len + instruction.len()].copy_from_slice(instruction);
+    len += instruction.len();
+    RawPacket::with_ecc(&data[..len])
+}
+
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::*;
+    use crate::receiver::Framer;
+
+    /// Serialise the given bytes to compare against a packet's output
+    pub fn serialise_bytes(data: &[u8]) -> (SerialiseBuffer, usize) {
+        let mut buf = SerialiseBuffer::default();
+        let len = serialise(data, &mut buf).unwrap();
+        (buf, len)
+    }
+
+    /// Serialise a packet and receive it with a `Framer`, returning the
+    /// received bytes
+    pub fn receive_bytes(packet: &impl DccPacket) -> RawPacket {
+        let mut buf = SerialiseBuffer::default();
+        let len = packet.serialise(&mut buf).unwrap();
+        let mut framer = Framer::new();
+        buf[..len]
+            .iter()
+            .by_vals()
+            .find_map(|bit| framer.push_bit(bit))
+            .unwrap()
+    }
+
+    /// Serialise a packet and decode it again, as a decoder receiving it
+    /// from the track would see it
+    pub fn receive(packet: &impl DccPacket) -> Packet {
+        Packet::parse(receive_bytes(packet).bytes()).unwrap()
+    }
 
     pub fn print_chunks(buf: &SerialiseBuffer, limit: usize) {
         println!("Preamble: {}", &buf[..15]);
@@ -0,0 +1,524 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Decoding of received packets into the packet types, e.g. the packets
+//! produced by `receiver::Framer`.
+//!
+//! Packets are identified by the partition of their first address byte:
+//! ```ignore
+//!  first byte | partition
+//!  -------------------------------------------------
+//!  0000 0000  | broadcast
+//!  0AAA AAAA  | multi-function decoder, short address
+//!  10AA AAAA  | accessory decoder
+//!  11AA AAAA  | multi-function decoder, long address
+//!  1111 1111  | idle
+//! ```
+//!
+//! Service-mode packets overlap with the short address partition, so they
+//! are decoded separately with `Packet::parse_service_mode`.
+
+use super::*;
+
+/// Any of the supported packet types, e.g. for storing different kinds of
+/// packet in the same collection or for the result of decoding a packet
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[allow(missing_docs)]
+pub enum Packet {
+    Reset(Reset),
+    Idle(Idle),
+    BroadcastStop(BroadcastStop),
+    SpeedAndDirection(SpeedAndDirection),
+    SpeedAndDirection128(SpeedAndDirection128),
+    FunctionControl(FunctionControl),
+    ProgrammingOnMain(ProgrammingOnMain),
+    DecoderControl(DecoderControl),
+    ConsistControl(ConsistControl),
+    BasicAccessory(BasicAccessory),
+    ExtendedAccessory(ExtendedAccessory),
+    AccessoryProgrammingOnMain(AccessoryProgrammingOnMain),
+    PagePreset(PagePreset),
+    Instruction(Instruction),
+    AddressOnly(AddressOnly),
+    PhysicalRegister(PhysicalRegister),
+    FactoryReset(FactoryReset),
+    AddressQuery(AddressQuery),
+    DecoderLock(DecoderLock),
+    /// A packet with a valid error detection byte which is not one of the
+    /// supported packet types
+    Unknown(RawPacket),
+}
+
+impl Packet {
+    /// Decode the bytes of an operations-mode packet, including the error
+    /// detection byte. Packets which are not recognised are returned as
+    /// `Packet::Unknown`.
+    ///
+    /// Returns `Error::TooLong` if there are more bytes than any supported
+    /// packet, or `Error::InvalidChecksum` if the error detection byte does
+    /// not match.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let raw = check(bytes)?;
+        Ok(decode(raw.data()).unwrap_or(Self::Unknown(raw)))
+    }
+
+    /// Decode the bytes of a packet received in service mode, including the
+    /// error detection byte. Packets which are not service-mode packets are
+    /// decoded as by `Packet::parse`, so that e.g. `Reset` packets are
+    /// recognised.
+    ///
+    /// Some service-mode packets share the same bit pattern. A two-byte
+    /// packet ending in `11111001` is decoded as an `AddressQuery`, and a
+    /// packet for physical register 1 is decoded as `AddressOnly`, which
+    /// uses the same register.
+    ///
+    /// Returns `Error::TooLong` if there are more bytes than any supported
+    /// packet, or `Error::InvalidChecksum` if the error detection byte does
+    /// not match.
+    pub fn parse_service_mode(bytes: &[u8]) -> Result<Self> {
+        let raw = check(bytes)?;
+        Ok(decode_service_mode(raw.data())
+            .or_else(|| decode(raw.data()))
+            .unwrap_or(Self::Unknown(raw)))
+    }
+
+    /// The packet held by this variant
+    fn inner(&self) -> &dyn DccPacket {
+        match self {
+            Self::Reset(packet) => packet,
+            Self::Idle(packet) => packet,
+            Self::BroadcastStop(packet) => packet,
+            Self::SpeedAndDirection(packet) => packet,
+            Self::SpeedAndDirection128(packet) => packet,
+            Self::FunctionControl(packet) => packet,
+            Self::ProgrammingOnMain(packet) => packet,
+            Self::DecoderControl(packet) => packet,
+            Self::ConsistControl(packet) => packet,
+            Self::BasicAccessory(packet) => packet,
+            Self::ExtendedAccessory(packet) => packet,
+            Self::AccessoryProgrammingOnMain(packet) => packet,
+            Self::PagePreset(packet) => packet,
+            Self::Instruction(packet) => packet,
+            Self::AddressOnly(packet) => packet,
+            Self::PhysicalRegister(packet) => packet,
+            Self::FactoryReset(packet) => packet,
+            Self::AddressQuery(packet) => packet,
+            Self::DecoderLock(packet) => packet,
+            Self::Unknown(packet) => packet,
+        }
+    }
+}
+
+impl DccPacket for Packet {
+    fn to_bytes(&self) -> RawPacket {
+        self.inner().to_bytes()
+    }
+
+    fn is_service_mode(&self) -> bool {
+        self.inner().is_service_mode()
+    }
+
+    fn preamble_bits(&self) -> usize {
+        self.inner().preamble_bits()
+    }
+
+    fn repeats(&self) -> u8 {
+        self.inner().repeats()
+    }
+}
+
+/// Check the length and error detection byte of a packet
+fn check(bytes: &[u8]) -> Result<RawPacket> {
+    let raw = RawPacket::new(bytes)?;
+    if bytes.iter().fold(0, |ecc, byte| ecc ^ byte) != 0 {
+        return Err(Error::InvalidChecksum);
+    }
+    Ok(raw)
+}
+
+/// Decode an operations-mode packet
+fn decode(data: &[u8]) -> Option<Packet> {
+    match *data {
+        [0x00, 0x00] => Some(Packet::Reset(Reset)),
+        [0xff, 0x00] => Some(Packet::Idle(Idle)),
+        [0x00, ref instruction @ ..] => {
+            BroadcastStop::parse(instruction).map(Packet::BroadcastStop)
+        }
+        [0x80..=0xbf, ..] => decode_accessory(data),
+        _ => {
+            let (address, len) = Address::read_bytes(data)?;
+            decode_multi_function(address, &data[len..])
+        }
+    }
+}
+
+/// Decode an instruction sent to a multi-function decoder. Each packet
+/// type only accepts its own instruction code, so the order in which they
+/// are tried does not matter.
+fn decode_multi_function(
+    address: Address,
+    instruction: &[u8],
+) -> Option<Packet> {
+    SpeedAndDirection::parse(address, instruction)
+        .map(Packet::SpeedAndDirection)
+        .or_else(|| {
+            SpeedAndDirection128::parse(address, instruction)
+                .map(Packet::SpeedAndDirection128)
+        })
+        .or_else(|| {
+            FunctionControl::parse(address, instruction)
+                .map(Packet::FunctionControl)
+        })
+        .or_else(|| {
+            ProgrammingOnMain::parse(address, instruction)
+                .map(Packet::ProgrammingOnMain)
+        })
+        .or_else(|| {
+            DecoderControl::parse(address, instruction)
+                .map(Packet::DecoderControl)
+        })
+        .or_else(|| {
+            ConsistControl::parse(address, instruction)
+                .map(Packet::ConsistControl)
+        })
+}
+
+/// Decode a packet sent to an accessory decoder
+fn decode_accessory(data: &[u8]) -> Option<Packet> {
+    BasicAccessory::parse(data)
+        .map(Packet::BasicAccessory)
+        .or_else(|| {
+            ExtendedAccessory::parse(data).map(Packet::ExtendedAccessory)
+        })
+        .or_else(|| {
+            AccessoryProgrammingOnMain::parse(data)
+                .map(Packet::AccessoryProgrammingOnMain)
+        })
+}
+
+/// Decode a service-mode packet. The page preset and factory reset packets
+/// are special cases of the physical register packet, and address-only
+/// packets use physical register 1.
+///
+/// The address query packet can also look like a physical register packet,
+/// so it is matched first: an address query to addresses 112-127 is more
+/// likely than writing `0xf9` to a register. Physical register 1 is always
+/// decoded as `AddressOnly`.
+fn decode_service_mode(data: &[u8]) -> Option<Packet> {
+    match *data {
+        [0b0111_1101, 0b0000_0001] => Some(Packet::PagePreset(PagePreset)),
+        [0b0111_1111, 0b0000_1000] => Some(Packet::FactoryReset(FactoryReset)),
+        [address, 0b1111_1001] => {
+            Some(Packet::AddressQuery(AddressQuery::address(address)))
+        }
+        [instr @ 0x70..=0x7f, address] if instr & 0x07 == 0 => {
+            Some(Packet::AddressOnly(if instr & 0x08 != 0 {
+                AddressOnly::Write { address }
+            } else {
+                AddressOnly::Verify { address }
+            }))
+        }
+        [0x70..=0x7f, _] => {
+            PhysicalRegister::parse(data).map(Packet::PhysicalRegister)
+        }
+        [0x70..=0x7f, _, _] => {
+            Instruction::parse(data).map(Packet::Instruction)
+        }
+        [0x00, 0b1111_1001, address] => DecoderLock::builder()
+            .address(address)
+            .ok()?
+            .build()
+            .ok()
+            .map(Packet::DecoderLock),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packets::test::{receive, receive_bytes};
+
+    macro_rules! assert_parses {
+        ($variant:ident, $packet:expr) => {{
+            let packet = $packet;
+            assert_eq!(receive(&packet), Packet::$variant(packet));
+        }};
+    }
+
+    macro_rules! assert_parses_service_mode {
+        ($variant:ident, $packet:expr) => {{
+            let packet = $packet;
+            assert_eq!(
+                Packet::parse_service_mode(receive_bytes(&packet).bytes()),
+                Ok(Packet::$variant(packet))
+            );
+        }};
+    }
+
+    #[test]
+    fn parse_baseline_packets() -> Result<()> {
+        assert_parses!(Reset, Reset);
+        assert_parses!(Idle, Idle);
+        assert_parses!(BroadcastStop, BroadcastStop::immediate());
+        assert_parses!(BroadcastStop, BroadcastStop::float());
+
+        // 128 speed step stop and e-stop sent to the broadcast address
+        for bytes in [[0x00, 0x3f, 0x01, 0x3e], [0x00, 0x3f, 0x80, 0xbf]] {
+            assert_eq!(
+                Packet::parse(&bytes),
+                Ok(Packet::BroadcastStop(BroadcastStop::immediate()))
+            );
+        }
+
+        let pkt = SpeedAndDirection::builder()
+            .address(10)?
+            .speed(14)?
+            .direction(Direction::Backward)
+            .build();
+        assert_eq!(pkt.address(), Address::Short(10));
+        assert_eq!(pkt.speed(), 14);
+        assert_eq!(pkt.direction(), Direction::Backward);
+        assert!(!pkt.is_e_stop());
+        assert_parses!(SpeedAndDirection, pkt);
+
+        let pkt = SpeedAndDirection::builder()
+            .long_address(1234)?
+            .e_stop(true)
+            .build();
+        assert_eq!(pkt.speed(), 0);
+        assert!(pkt.is_e_stop());
+        assert_parses!(SpeedAndDirection, pkt);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_extended_packets() -> Result<()> {
+        let pkt = SpeedAndDirection128::builder()
+            .long_address(10239)?
+            .speed(126)?
+            .build();
+        assert_eq!(pkt.address(), Address::Long(10239));
+        assert_eq!(pkt.speed(), 126);
+        assert_eq!(pkt.direction(), Direction::Forward);
+        assert_parses!(SpeedAndDirection128, pkt);
+
+        let mut functions = Functions::new();
+        functions.set(0, true)?.set(3, true)?.set(68, true)?;
+        for group in FunctionGroup::ALL {
+            let pkt = FunctionControl::builder()
+                .address(3)?
+                .group(group)
+                .functions(functions)
+                .build()?;
+            let Packet::FunctionControl(parsed) = receive(&pkt) else {
+                panic!("not a function control packet");
+            };
+            assert_eq!(parsed.address(), Address::Short(3));
+            assert_eq!(parsed.group(), group);
+            for function in 0..=Functions::MAX {
+                let in_group = matches!(
+                    (group, function),
+                    (FunctionGroup::F0ToF4, 0..=4)
+                        | (FunctionGroup::F61ToF68, 61..=68)
+                );
+                assert_eq!(
+                    parsed.functions().get(function),
+                    in_group && functions.get(function)
+                );
+            }
+        }
+
+        let pkt = ProgrammingOnMain::builder()
+            .long_address(1234)?
+            .cv_address(1024)?
+            .write_byte(0xa5)
+            .build()?;
+        assert_eq!(pkt.cv_address(), 1024);
+        assert_eq!(
+            pkt.instruction_type(),
+            InstructionType::WriteCvByte { value: 0xa5 }
+        );
+        assert_parses!(ProgrammingOnMain, pkt);
+        assert_parses!(
+            ProgrammingOnMain,
+            ProgrammingOnMain::builder()
+                .address(3)?
+                .cv_address(29)?
+                .verify_bit(5, true)?
+                .build()?
+        );
+
+        let pkt = BasicAccessory::builder()
+            .broadcast(3)?
+            .activate(false)
+            .direction(TurnoutDirection::Thrown)
+            .build()?;
+        assert_eq!(pkt.address(), 511);
+        assert_eq!(pkt.output_pair(), 3);
+        assert!(!pkt.activate());
+        assert_parses!(BasicAccessory, pkt);
+
+        let pkt = ExtendedAccessory::builder()
+            .address(1234)?
+            .aspect(31)
+            .build()?;
+        assert_eq!(pkt.address(), 1234);
+        assert_eq!(pkt.aspect(), 31);
+        assert_parses!(ExtendedAccessory, pkt);
+
+        let pkt = AccessoryProgrammingOnMain::builder()
+            .extended_address(2000)?
+            .cv_address(29)?
+            .verify_bit(3, true)?
+            .build()?;
+        assert_eq!(pkt.target(), AccessoryTarget::Extended(2000));
+        assert_parses!(AccessoryProgrammingOnMain, pkt);
+        assert_parses!(
+            AccessoryProgrammingOnMain,
+            AccessoryProgrammingOnMain::builder()
+                .board_address(300)?
+                .cv_address(1)?
+                .write_byte(3)
+                .build()?
+        );
+
+        for instruction in [
+            DecoderControlInstruction::Reset,
+            DecoderControlInstruction::HardReset,
+            DecoderControlInstruction::FactoryTest,
+            DecoderControlInstruction::SetAdvancedAddressing(true),
+            DecoderControlInstruction::SetAdvancedAddressing(false),
+            DecoderControlInstruction::AcknowledgementRequest,
+        ] {
+            assert_parses!(
+                DecoderControl,
+                DecoderControl::new(Address::Long(100), instruction)
+            );
+        }
+
+        let pkt = ConsistControl::set(
+            Address::Short(3),
+            127,
+            ConsistDirection::Reversed,
+        )?;
+        assert_eq!(pkt.consist_address(), 127);
+        assert_eq!(pkt.direction(), ConsistDirection::Reversed);
+        assert_parses!(ConsistControl, pkt);
+        assert_parses!(ConsistControl, ConsistControl::clear(Address::Long(3)));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_service_mode_packets() -> Result<()> {
+        assert_parses_service_mode!(PagePreset, PagePreset);
+        assert_parses_service_mode!(FactoryReset, FactoryReset);
+        assert_parses_service_mode!(AddressQuery, AddressQuery::address(3));
+        assert_parses_service_mode!(AddressQuery, AddressQuery::address(0x75));
+        assert_parses_service_mode!(AddressOnly, AddressOnly::write(3)?);
+        assert_parses_service_mode!(AddressOnly, AddressOnly::verify(3)?);
+
+        let pkt = Instruction::builder()
+            .cv_address(1023)?
+            .write_bit(7, true)?
+            .build()?;
+        assert_eq!(pkt.cv_address(), 1023);
+        assert_eq!(
+            pkt.instruction_type(),
+            InstructionType::WriteCvBit {
+                offset: 7,
+                value: true
+            }
+        );
+        assert_parses_service_mode!(Instruction, pkt);
+
+        let pkt = PhysicalRegister::builder()
+            .operation(Operation::Write)
+            .register(PhysicalRegister::ACCELERATION)?
+            .value(3)
+            .build()?;
+        assert_eq!(pkt.operation(), Operation::Write);
+        assert_eq!(pkt.register(), PhysicalRegister::ACCELERATION);
+        assert_eq!(pkt.value(), 3);
+        assert_parses_service_mode!(PhysicalRegister, pkt);
+
+        let pkt = DecoderLock::builder().address(3)?.build()?;
+        assert_eq!(pkt.address(), 3);
+        assert_parses_service_mode!(DecoderLock, pkt);
+
+        // operations-mode packets are still recognised in service mode
+        assert_parses_service_mode!(Reset, Reset);
+        Ok(())
+    }
+
+    #[test]
+    fn packet_forwards_to_inner_packet() -> Result<()> {
+        let pkt = ProgrammingOnMain::builder()
+            .address(3)?
+            .cv_address(1)?
+            .write_byte(4)
+            .build()?;
+        let packet = Packet::ProgrammingOnMain(pkt);
+        assert_eq!(packet.to_bytes(), pkt.to_bytes());
+        assert_eq!(packet.to_bytes().bytes(), [0x03, 0xec, 0x00, 0x04, 0xeb]);
+        assert_eq!(packet.repeats(), 2);
+        assert!(!packet.is_service_mode());
+        assert_eq!(packet.preamble_bits(), 15);
+
+        let packet = Packet::FactoryReset(FactoryReset);
+        assert!(packet.is_service_mode());
+        assert_eq!(packet.preamble_bits(), 20);
+        assert_eq!(packet.repeats(), 5);
+
+        // unknown packets are sent unchanged
+        let raw = RawPacket::new(&[0xe8, 0x00, 0x60, 0x88])?;
+        assert_eq!(
+            receive_bytes(&Packet::parse(raw.bytes())?).bytes(),
+            raw.bytes()
+        );
+        assert_eq!(RawPacket::new(&[0; 7]), Err(Error::TooLong));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_and_invalid_packets() {
+        // reserved long address partition
+        let bytes = [0xe8, 0x00, 0x60, 0x88];
+        let Ok(Packet::Unknown(raw)) = Packet::parse(&bytes) else {
+            panic!("reserved address was decoded");
+        };
+        assert_eq!(raw.bytes(), bytes);
+
+        // broadcast speeds which are not a stop
+        let bytes = [0x00, 0x65, 0x65];
+        assert!(matches!(Packet::parse(&bytes), Ok(Packet::Unknown(_))));
+        let bytes = [0x00, 0x3f, 0x02, 0x3d];
+        assert!(matches!(Packet::parse(&bytes), Ok(Packet::Unknown(_))));
+
+        // the factory test instruction may be sent with either `F` bit
+        assert_eq!(
+            Packet::parse(&[0x03, 0x03, 0x00]),
+            Ok(Packet::DecoderControl(DecoderControl::new(
+                Address::Short(3),
+                DecoderControlInstruction::FactoryTest
+            )))
+        );
+
+        // service-mode packets look like short addresses in operations mode
+        assert_eq!(
+            Packet::parse(&[0x7d, 0x01, 0x7c]),
+            Ok(Packet::DecoderControl(DecoderControl::new(
+                Address::Short(125),
+                DecoderControlInstruction::HardReset
+            )))
+        );
+
+        assert_eq!(
+            Packet::parse(&[0x03, 0x60, 0x00]),
+            Err(Error::InvalidChecksum)
+        );
+        assert_eq!(Packet::parse(&[0; 7]), Err(Error::TooLong));
+    }
+}
@@ -7,9 +7,15 @@
 //!
 //! <https://www.nmra.org/sites/default/files/standards/sandrp/pdf/S-9.2.3_2012_07.pdf>
 
-use super::{Error, Result, SerialiseBuffer};
+use super::{DccPacket, Error, RawPacket, Result};
 
-#[derive(Copy, Clone)]
+/// Number of times each service-mode packet is sent. S-9.2.3 requires
+/// decoders to receive at least five identical packets before acting on
+/// most instructions.
+const SERVICE_MODE_REPEATS: u8 = 5;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 #[allow(missing_docs)]
 pub enum Operation {
     Verify,
@@ -17,27 +23,35 @@ pub enum Operation {
 }
 
 /// "A packet sequence sent to guarantee the contents of the page register"
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct PagePreset;
 
-impl PagePreset {
-    /// Serialise the Instruction packet into the provided bufffer. Returns the
-    /// number of bits written or an `Error::TooLong` if the buffer has
-    /// insufficient capacity
-    pub fn serialise(&self, buf: &mut SerialiseBuffer) -> Result<usize> {
-        super::serialise(&[0b01111101, 0b00000001, 0b01111100], buf)
+impl DccPacket for PagePreset {
+    fn to_bytes(&self) -> RawPacket {
+        RawPacket::with_ecc(&[0b01111101, 0b00000001])
+    }
+
+    fn is_service_mode(&self) -> bool {
+        true
+    }
+
+    fn repeats(&self) -> u8 {
+        SERVICE_MODE_REPEATS
     }
 }
 
 /// Instruction types supported by the `Instruction` packet:
 /// * `VerifyByte`: decoder compares its recorded CV value against the provided
-/// data byte and responds with an acknowledgement if they match
+///   data byte and responds with an acknowledgement if they match
 /// * `WriteCvByte`: decoder writes the provided data byte into the specified
-/// CV slot and may respond with an acknowledgement on successful write
+///   CV slot and may respond with an acknowledgement on successful write
 /// * `VerifyCvBit`: Compare the given bit with the bit in the specified
-/// position within the CV and repond with an acknowledgement if they match
+///   position within the CV and repond with an acknowledgement if they match
 /// * `WriteCvBit`: Write the given bit into the specified position within the
-/// specified CV. Decoder may respond with an acknowledgement on success
-#[derive(Copy, Clone)]
+///   specified CV. Decoder may respond with an acknowledgement on success
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 #[allow(missing_docs)]
 pub enum InstructionType {
     WriteCvBit { offset: u8, value: bool },
@@ -46,36 +60,24 @@ pub enum InstructionType {
     VerifyCvByte { value: u8 },
 }
 
-/// The `Instruction` service-mode packet instructs the decoder to write or
-/// verify the specified 10-bit CV address against the provided data byte
-pub struct Instruction {
-    typ: InstructionType,
-    cv_address: u16,
-}
-
-impl Instruction {
-    /// Create a builder for the Instruction packet
-    pub fn builder() -> InstructionBuilder {
-        InstructionBuilder::default()
-    }
-
-    /// Serialise the Instruction packet into the provided bufffer. Returns the
-    /// number of bits written or an `Error::TooLong` if the buffer has
-    /// insufficient capacity
-    pub fn serialise(&self, buf: &mut SerialiseBuffer) -> Result<usize> {
+impl InstructionType {
+    /// Encode the `CCAA AAAAAAAA DDDDDDDD` part of a CV access instruction,
+    /// which is shared between the service-mode and operations-mode forms.
+    /// The upper nibble of the first byte is left empty for the caller to
+    /// fill in with the packet type.
+    pub(super) fn encode(&self, cv_address: u16) -> [u8; 3] {
         // write the first two bits of CV address into this byte now and fill
-        // in the packet type later
-        let mut type_and_start_of_address = 0x70;
-        type_and_start_of_address |= (self.cv_address >> 8) as u8;
+        // in the instruction type later
+        let mut type_and_start_of_address = (cv_address >> 8) as u8 & 0x03;
 
         // Pull out the lower 8 bits of the CV address
-        let rest_of_address = (self.cv_address & 0x00ff) as u8;
+        let rest_of_address = (cv_address & 0x00ff) as u8;
 
         // Calculate the "data" byte: in "byte" modes this is simply the
         // provided data byte; in "bit" modes this is a combination of offset
         // and value
         #[allow(clippy::unusual_byte_groupings)]
-        let data = match self.typ {
+        let data = match *self {
             InstructionType::WriteCvBit { offset, value } => {
                 type_and_start_of_address |= 0b0000_10_00;
                 // padding - 1=write - data - offset
@@ -87,7 +89,6 @@ impl Instruction {
             InstructionType::VerifyCvBit { offset, value } => {
                 type_and_start_of_address |= 0b0000_10_00;
                 // padding - 0=verify - data - offset
-                #[allow(clippy::unusual_byte_groupings)]
                 let mut data = 0b111_0_0000;
                 data |= offset;
                 data |= (value as u8) << 3;
@@ -103,15 +104,149 @@ impl Instruction {
             }
         };
 
-        super::serialise(
-            &[
-                type_and_start_of_address,
-                rest_of_address,
-                data,
-                type_and_start_of_address ^ rest_of_address ^ data,
-            ],
-            buf,
-        )
+        [type_and_start_of_address, rest_of_address, data]
+    }
+
+    /// Decode the `CCAA AAAAAAAA DDDDDDDD` part of a CV access instruction
+    /// into the instruction type and the 10-bit CV address. The upper
+    /// nibble of the first byte is ignored. Returns `None` for the
+    /// reserved instruction type.
+    pub(super) fn decode(bytes: [u8; 3]) -> Option<(Self, u16)> {
+        let [type_and_start_of_address, rest_of_address, data] = bytes;
+        let cv_address = (type_and_start_of_address as u16 & 0x03) << 8
+            | rest_of_address as u16;
+
+        let typ = match (type_and_start_of_address >> 2) & 0x03 {
+            0b01 => InstructionType::VerifyCvByte { value: data },
+            0b11 => InstructionType::WriteCvByte { value: data },
+            0b10 if data & 0xe0 == 0xe0 => {
+                let offset = data & 0x07;
+                let value = data & 0x08 != 0;
+                if data & 0x10 != 0 {
+                    InstructionType::WriteCvBit { offset, value }
+                } else {
+                    InstructionType::VerifyCvBit { offset, value }
+                }
+            }
+            _ => return None,
+        };
+        Some((typ, cv_address))
+    }
+}
+
+/// The CV number and instruction type being collected by the builder of
+/// any of the CV access packets, which share the same validation
+#[derive(Default)]
+pub(super) struct CvAccess {
+    cv_address: Option<u16>,
+    typ: Option<InstructionType>,
+}
+
+impl CvAccess {
+    /// Set the CV number, between 1 and 1024, storing the 10-bit address
+    /// which is one less. Returns `Error::InvalidAddress` if it is out of
+    /// range.
+    pub(super) fn cv_address(&mut self, cv_address: u16) -> Result<()> {
+        if (1..=0x0400).contains(&cv_address) {
+            self.cv_address = Some(cv_address - 1);
+            Ok(())
+        } else {
+            Err(Error::InvalidAddress)
+        }
+    }
+
+    pub(super) fn write_byte(&mut self, value: u8) {
+        self.typ = Some(InstructionType::WriteCvByte { value });
+    }
+
+    pub(super) fn verify_byte(&mut self, value: u8) {
+        self.typ = Some(InstructionType::VerifyCvByte { value });
+    }
+
+    /// Returns `Error::InvalidOffset` if the offset is not between 0 and 7
+    pub(super) fn write_bit(&mut self, offset: u8, value: bool) -> Result<()> {
+        if offset < 0x08 {
+            self.typ = Some(InstructionType::WriteCvBit { offset, value });
+            Ok(())
+        } else {
+            Err(Error::InvalidOffset)
+        }
+    }
+
+    /// Returns `Error::InvalidOffset` if the offset is not between 0 and 7
+    pub(super) fn verify_bit(&mut self, offset: u8, value: bool) -> Result<()> {
+        if offset < 0x08 {
+            self.typ = Some(InstructionType::VerifyCvBit { offset, value });
+            Ok(())
+        } else {
+            Err(Error::InvalidOffset)
+        }
+    }
+
+    /// The instruction type and 10-bit CV address, or
+    /// `Error::MissingField` if either has not been set
+    pub(super) fn build(&self) -> Result<(InstructionType, u16)> {
+        Ok((
+            self.typ.ok_or(Error::MissingField)?,
+            self.cv_address.ok_or(Error::MissingField)?,
+        ))
+    }
+}
+
+/// The `Instruction` service-mode packet instructs the decoder to write or
+/// verify the specified 10-bit CV address against the provided data byte
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Instruction {
+    typ: InstructionType,
+    cv_address: u16,
+}
+
+impl Instruction {
+    /// Create a builder for the Instruction packet
+    pub fn builder() -> InstructionBuilder {
+        InstructionBuilder::default()
+    }
+
+    /// Decode the bytes of an `Instruction` packet, excluding the error
+    /// detection byte
+    pub(super) fn parse(bytes: &[u8]) -> Option<Self> {
+        match *bytes {
+            [first, second, data] if first & 0xf0 == 0x70 => {
+                let (typ, cv_address) =
+                    InstructionType::decode([first, second, data])?;
+                Some(Self { typ, cv_address })
+            }
+            _ => None,
+        }
+    }
+
+    /// The write or verify operation to perform on the CV
+    pub fn instruction_type(&self) -> InstructionType {
+        self.typ
+    }
+
+    /// The CV number, between 1 and 1024
+    pub fn cv_address(&self) -> u16 {
+        self.cv_address + 1
+    }
+}
+
+impl DccPacket for Instruction {
+    fn to_bytes(&self) -> RawPacket {
+        let [mut type_and_start_of_address, rest_of_address, data] =
+            self.typ.encode(self.cv_address);
+        type_and_start_of_address |= 0x70;
+
+        RawPacket::with_ecc(&[type_and_start_of_address, rest_of_address, data])
+    }
+
+    fn is_service_mode(&self) -> bool {
+        true
+    }
+
+    fn repeats(&self) -> u8 {
+        SERVICE_MODE_REPEATS
     }
 }
 
@@ -119,69 +254,56 @@ impl Instruction {
 /// are created
 #[derive(Default)]
 pub struct InstructionBuilder {
-    cv_address: Option<u16>,
-    typ: Option<InstructionType>,
+    cv: CvAccess,
 }
 
 impl InstructionBuilder {
-    /// Set the address. Returns `Error::InvalidAddress` if the provided CV
-    /// addresss does not fit into 10 bits.
+    /// Set the CV number, between 1 and 1024. Returns
+    /// `Error::InvalidAddress` if the CV address does not fit into 10 bits.
     ///
     /// From the standard: "The configuration variable being addressed is the
     /// provided 10-bit address plus 1", so this method subtracts 1 from the
     /// supplied CV number in order to determine its address.
     pub fn cv_address(&mut self, cv_address: u16) -> Result<&mut Self> {
-        if 0 < cv_address && cv_address < 0x0400 {
-            self.cv_address = Some(cv_address - 1);
-            Ok(self)
-        } else {
-            Err(Error::InvalidAddress)
-        }
+        self.cv.cv_address(cv_address)?;
+        Ok(self)
     }
 
     /// Create a `WriteCvByte` packet with the provided byte value
     pub fn write_byte(&mut self, value: u8) -> &mut Self {
-        self.typ = Some(InstructionType::WriteCvByte { value });
+        self.cv.write_byte(value);
         self
     }
 
     /// Create a `VerifyCvByte` packet with the provided byte value
     pub fn verify_byte(&mut self, value: u8) -> &mut Self {
-        self.typ = Some(InstructionType::VerifyCvByte { value });
+        self.cv.verify_byte(value);
         self
     }
 
     /// Create a `WriteCvBit` packet with the provided bit offset and value
     pub fn write_bit(&mut self, offset: u8, value: bool) -> Result<&mut Self> {
-        if offset < 0x08 {
-            self.typ = Some(InstructionType::WriteCvBit { offset, value });
-            Ok(self)
-        } else {
-            Err(Error::InvalidOffset)
-        }
+        self.cv.write_bit(offset, value)?;
+        Ok(self)
     }
 
     /// Create a `VerifyCvBit` packet with the provided bit offset and value
     pub fn verify_bit(&mut self, offset: u8, value: bool) -> Result<&mut Self> {
-        if offset < 0x08 {
-            self.typ = Some(InstructionType::VerifyCvBit { offset, value });
-            Ok(self)
-        } else {
-            Err(Error::InvalidOffset)
-        }
+        self.cv.verify_bit(offset, value)?;
+        Ok(self)
     }
 
     /// Validate that all fields are present and return an Instruction packet
     pub fn build(&mut self) -> Result<Instruction> {
-        Ok(Instruction {
-            typ: self.typ.ok_or(Error::MissingField)?,
-            cv_address: self.cv_address.ok_or(Error::MissingField)?,
-        })
+        let (typ, cv_address) = self.cv.build()?;
+        Ok(Instruction { typ, cv_address })
     }
 }
 
 /// `AddressOnly` instructs the decoder to set its short-mode address to the
 /// provided value and to clear its extended addressing and consist CVs
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 #[allow(missing_docs)]
 pub enum AddressOnly {
     Write { address: u8 },
@@ -208,11 +330,10 @@ impl AddressOnly {
             Err(Error::InvalidAddress)
         }
     }
+}
 
-    /// Serialise the Instruction packet into the provided bufffer. Returns the
-    /// number of bits written or an `Error::TooLong` if the buffer has
-    /// insufficient capacity
-    pub fn serialise(&self, buf: &mut SerialiseBuffer) -> Result<usize> {
+impl DccPacket for AddressOnly {
+    fn to_bytes(&self) -> RawPacket {
         let mut instr = 0b0111_0000;
         let address = match self {
             AddressOnly::Write { address } => {
@@ -221,7 +342,15 @@ impl AddressOnly {
             }
             AddressOnly::Verify { address } => *address,
         };
-        super::serialise(&[instr, address, instr ^ address], buf)
+        RawPacket::with_ecc(&[instr, address])
+    }
+
+    fn is_service_mode(&self) -> bool {
+        true
+    }
+
+    fn repeats(&self) -> u8 {
+        SERVICE_MODE_REPEATS
     }
 }
 
@@ -229,6 +358,8 @@ impl AddressOnly {
 /// the value stored in each of the eight "physical registers". These correspond
 /// to various CV slots depending on whether it is a locomotove or an accessory
 /// decoder.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct PhysicalRegister {
     operation: Operation,
     register: u8,
@@ -258,10 +389,41 @@ impl PhysicalRegister {
         PhysicalRegisterBuilder::default()
     }
 
-    /// Serialise the PhysicalRegister packet into the provided bufffer. Returns
-    /// the number of bits written or an `Error::TooLong` if the buffer has
-    /// insufficient capacity
-    pub fn serialise(&self, buf: &mut SerialiseBuffer) -> Result<usize> {
+    /// Decode the bytes of a `PhysicalRegister` packet, excluding the error
+    /// detection byte
+    pub(super) fn parse(bytes: &[u8]) -> Option<Self> {
+        match *bytes {
+            [instr, value] if instr & 0xf0 == 0x70 => Some(Self {
+                operation: if instr & 0x08 != 0 {
+                    Operation::Write
+                } else {
+                    Operation::Verify
+                },
+                register: instr & 0x07,
+                value,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether the register is written or verified
+    pub fn operation(&self) -> Operation {
+        self.operation
+    }
+
+    /// The register number, between 1 and 8
+    pub fn register(&self) -> u8 {
+        self.register + 1
+    }
+
+    /// The value to write or verify
+    pub fn value(&self) -> u8 {
+        self.value
+    }
+}
+
+impl DccPacket for PhysicalRegister {
+    fn to_bytes(&self) -> RawPacket {
         let mut instr = 0b0111_0000;
 
         if let Operation::Write = self.operation {
@@ -270,7 +432,15 @@ impl PhysicalRegister {
 
         instr |= self.register;
 
-        super::serialise(&[instr, self.value, instr ^ self.value], buf)
+        RawPacket::with_ecc(&[instr, self.value])
+    }
+
+    fn is_service_mode(&self) -> bool {
+        true
+    }
+
+    fn repeats(&self) -> u8 {
+        SERVICE_MODE_REPEATS
     }
 }
 
@@ -319,18 +489,27 @@ impl PhysicalRegisterBuilder {
 }
 
 /// Reset decoder to factory-default condition
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct FactoryReset;
 
-impl FactoryReset {
-    /// Serialise the PhysicalRegister packet into the provided bufffer. Returns
-    /// the number of bits written or an `Error::TooLong` if the buffer has
-    /// insufficient capacity
-    pub fn serialise(&self, buf: &mut SerialiseBuffer) -> Result<usize> {
-        super::serialise(&[0b01111111, 0b00001000, 0b01110111], buf)
+impl DccPacket for FactoryReset {
+    fn to_bytes(&self) -> RawPacket {
+        RawPacket::with_ecc(&[0b01111111, 0b00001000])
+    }
+
+    fn is_service_mode(&self) -> bool {
+        true
+    }
+
+    fn repeats(&self) -> u8 {
+        SERVICE_MODE_REPEATS
     }
 }
 
 /// Query an older decoder to verify its address
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct AddressQuery {
     address: u8,
 }
@@ -341,17 +520,31 @@ impl AddressQuery {
         AddressQuery { address }
     }
 
-    /// Serialise the PhysicalRegister packet into the provided bufffer. Returns
-    /// the number of bits written or an `Error::TooLong` if the buffer has
-    /// insufficient capacity
-    pub fn serialise(&self, buf: &mut SerialiseBuffer) -> Result<usize> {
+    /// The address being queried
+    pub fn queried_address(&self) -> u8 {
+        self.address
+    }
+}
+
+impl DccPacket for AddressQuery {
+    fn to_bytes(&self) -> RawPacket {
         let instr = 0b11111001;
-        super::serialise(&[self.address, instr, self.address ^ instr], buf)
+        RawPacket::with_ecc(&[self.address, instr])
+    }
+
+    fn is_service_mode(&self) -> bool {
+        true
+    }
+
+    fn repeats(&self) -> u8 {
+        SERVICE_MODE_REPEATS
     }
 }
 
 /// Instruct any decoder not matching the given address to ignore any subsequent
 /// service-mode packets
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct DecoderLock {
     address: u8,
 }
@@ -362,12 +555,24 @@ impl DecoderLock {
         DecoderLockBuilder::default()
     }
 
-    /// Serialise the PhysicalRegister packet into the provided bufffer. Returns
-    /// the number of bits written or an `Error::TooLong` if the buffer has
-    /// insufficient capacity
-    pub fn serialise(&self, buf: &mut SerialiseBuffer) -> Result<usize> {
+    /// The address of the decoder which is not locked
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+}
+
+impl DccPacket for DecoderLock {
+    fn to_bytes(&self) -> RawPacket {
         let instr = 0b11111001;
-        super::serialise(&[0, instr, self.address, self.address ^ instr], buf)
+        RawPacket::with_ecc(&[0, instr, self.address])
+    }
+
+    fn is_service_mode(&self) -> bool {
+        true
+    }
+
+    fn repeats(&self) -> u8 {
+        SERVICE_MODE_REPEATS
     }
 }
 
@@ -403,8 +608,22 @@ impl DecoderLockBuilder {
 mod test {
     use super::*;
     use crate::packets::test::print_chunks;
+    use crate::packets::SerialiseBuffer;
     use bitvec::prelude::*;
 
+    #[test]
+    fn instruction_cv_range() {
+        let mut builder = Instruction::builder();
+        assert_eq!(builder.cv_address(0).err(), Some(Error::InvalidAddress));
+        assert_eq!(builder.cv_address(1025).err(), Some(Error::InvalidAddress));
+        assert_eq!(
+            builder.write_bit(8, true).err(),
+            Some(Error::InvalidOffset)
+        );
+        let pkt = builder.cv_address(1024).unwrap().verify_byte(3).build();
+        assert_eq!(pkt.unwrap().to_bytes().bytes()[..2], [0x77, 0xff]);
+    }
+
     #[test]
     fn serialise_instruction_packet_write_byte() {
         // [    preamble    ] S      WWAA SAAA A AAA A_S_DD DD_DD DD_S_E EEE_E EEES
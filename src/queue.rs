@@ -0,0 +1,332 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Bounded priority queue for one-shot packets such as accessory commands,
+//! programming on the main and emergency stops. Each packet is sent a
+//! given number of times before being dropped from the queue.
+
+use crate::{BufferType, Error, BUFFER_SIZE};
+use bitvec::prelude::*;
+
+/// Priority of a queued packet. Higher priority packets are always sent
+/// before lower priority ones; packets of equal priority are sent in the
+/// order in which they were queued.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum Priority {
+    /// Background traffic
+    Low,
+    /// Regular commands
+    #[default]
+    Normal,
+    /// Commands which should jump ahead of regular traffic
+    High,
+    /// Emergency stops
+    Emergency,
+}
+
+/// Options controlling how a queued packet is transmitted
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct TxOptions {
+    /// Priority of the packet within the queue
+    pub priority: Priority,
+    /// Number of times to send the packet. A value of zero is treated as
+    /// one.
+    pub repeats: u8,
+    /// Whether this is a service-mode packet. Service-mode packets are
+    /// sent with the longer service-mode preamble, and are sent straight
+    /// after one another rather than being separated by `Idle` packets.
+    ///
+    /// S-9.2.3 requires service-mode packets to be sent as unbroken
+    /// sequences, e.g. resets, then the repeated instruction, then resets.
+    /// All the repeats of a service-mode packet are sent back-to-back, and
+    /// once a service-mode packet has been sent the queue keeps sending
+    /// service-mode packets until there are none left. The whole sequence
+    /// should therefore be queued before it starts.
+    pub service_mode: bool,
+}
+
+impl Default for TxOptions {
+    fn default() -> Self {
+        Self {
+            priority: Priority::default(),
+            repeats: 1,
+            service_mode: false,
+        }
+    }
+}
+
+impl TxOptions {
+    /// Create a set of options with the given priority and repeat count
+    pub fn new(priority: Priority, repeats: u8) -> Self {
+        Self {
+            priority,
+            repeats,
+            service_mode: false,
+        }
+    }
+
+    /// Create a set of options for a service-mode packet which is sent
+    /// `repeats` times
+    pub fn service_mode(repeats: u8) -> Self {
+        Self {
+            repeats,
+            service_mode: true,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    buffer: BufferType,
+    len: usize,
+    options: TxOptions,
+    sequence: u32,
+}
+
+/// Fixed-capacity priority queue holding up to `N` serialised packets
+pub struct PacketQueue<const N: usize> {
+    entries: [Option<Entry>; N],
+    sequence: u32,
+    in_service_mode: bool,
+}
+
+impl<const N: usize> Default for PacketQueue<N> {
+    fn default() -> Self {
+        Self {
+            entries: [None; N],
+            sequence: 0,
+            in_service_mode: false,
+        }
+    }
+}
+
+impl<const N: usize> PacketQueue<N> {
+    /// Create an empty queue
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a serialised packet. Returns `Error::TooLong` if the packet
+    /// does not fit into the internal buffer, or `Error::Full` if the
+    /// queue has no room for another packet.
+    pub fn push(
+        &mut self,
+        buf: &BitSlice<u8, Msb0>,
+        options: TxOptions,
+    ) -> Result<(), Error> {
+        if buf.len() > BUFFER_SIZE {
+            return Err(Error::TooLong);
+        }
+        let slot = self
+            .entries
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(Error::Full)?;
+
+        let mut buffer = BufferType::default();
+        buffer[..buf.len()].copy_from_bitslice(buf);
+        *slot = Some(Entry {
+            buffer,
+            len: buf.len(),
+            options: TxOptions {
+                repeats: options.repeats.max(1),
+                ..options
+            },
+            sequence: self.sequence,
+        });
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Copy the highest priority packet into `out`, returning its length
+    /// in bits and the options it was queued with, or `None` if the queue
+    /// is empty. Packets with repeats remaining are moved to the back of
+    /// their priority level so that other packets are interleaved with the
+    /// repeats. Service-mode packets are the exception: their repeats are
+    /// sent back-to-back, and operations-mode packets are held back until
+    /// every queued service-mode packet has been sent.
+    ///
+    /// Panics if `out` is shorter than the packet.
+    pub fn pop(
+        &mut self,
+        out: &mut BitSlice<u8, Msb0>,
+    ) -> Option<(usize, TxOptions)> {
+        // Compare ages relative to the current sequence number rather than
+        // the sequence numbers themselves, so that the order survives the
+        // counter wrapping around
+        let sequence = self.sequence;
+        let in_service_mode = self.in_service_mode
+            && self
+                .entries
+                .iter()
+                .flatten()
+                .any(|entry| entry.options.service_mode);
+        let slot = self
+            .entries
+            .iter_mut()
+            .filter(|slot| {
+                matches!(slot, Some(entry)
+                    if entry.options.service_mode || !in_service_mode)
+            })
+            .max_by_key(|slot| {
+                slot.as_ref().map(|entry| {
+                    let age = sequence.wrapping_sub(entry.sequence);
+                    (entry.options.priority, age)
+                })
+            })?;
+
+        let entry = slot.as_mut()?;
+        let len = entry.len;
+        let options = entry.options;
+        out[..len].copy_from_bitslice(&entry.buffer[..len]);
+
+        self.in_service_mode = options.service_mode;
+        entry.options.repeats -= 1;
+        if entry.options.repeats == 0 {
+            *slot = None;
+        } else if !options.service_mode {
+            entry.sequence = self.sequence;
+            self.sequence = self.sequence.wrapping_add(1);
+        }
+        Some((len, options))
+    }
+
+    /// Number of packets in the queue
+    pub fn len(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    /// Returns `true` if there are no packets in the queue
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if there is no room for another packet
+    pub fn is_full(&self) -> bool {
+        self.entries.iter().all(Option::is_some)
+    }
+
+    /// Drop every packet in the queue
+    pub fn clear(&mut self) {
+        self.entries = [None; N];
+        self.in_service_mode = false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pop_byte<const N: usize>(queue: &mut PacketQueue<N>) -> Option<u8> {
+        let mut out = BufferType::default();
+        let (len, _) = queue.pop(&mut out)?;
+        assert_eq!(len, 8);
+        Some(out[..8].load_be())
+    }
+
+    #[test]
+    fn priority_order() {
+        let mut queue = PacketQueue::<4>::new();
+        queue
+            .push([1].view_bits(), TxOptions::new(Priority::Low, 1))
+            .unwrap();
+        queue.push([2].view_bits(), TxOptions::default()).unwrap();
+        queue
+            .push([3].view_bits(), TxOptions::new(Priority::Emergency, 1))
+            .unwrap();
+        queue.push([4].view_bits(), TxOptions::default()).unwrap();
+        assert!(queue.is_full());
+        assert_eq!(
+            queue.push([5].view_bits(), TxOptions::default()),
+            Err(Error::Full)
+        );
+
+        assert_eq!(pop_byte(&mut queue), Some(3));
+        assert_eq!(pop_byte(&mut queue), Some(2));
+        assert_eq!(pop_byte(&mut queue), Some(4));
+        assert_eq!(pop_byte(&mut queue), Some(1));
+        assert_eq!(pop_byte(&mut queue), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn repeats_are_interleaved() {
+        let mut queue = PacketQueue::<4>::new();
+        queue
+            .push([1].view_bits(), TxOptions::new(Priority::Normal, 3))
+            .unwrap();
+        queue
+            .push([2].view_bits(), TxOptions::new(Priority::Normal, 2))
+            .unwrap();
+        assert_eq!(queue.len(), 2);
+
+        for expected in [1, 2, 1, 2, 1] {
+            assert_eq!(pop_byte(&mut queue), Some(expected));
+        }
+        assert_eq!(pop_byte(&mut queue), None);
+    }
+
+    #[test]
+    fn order_survives_sequence_wraparound() {
+        let mut queue = PacketQueue::<4>::new();
+        queue.sequence = u32::MAX - 1;
+        for byte in 1..=4 {
+            queue
+                .push([byte].view_bits(), TxOptions::default())
+                .unwrap();
+        }
+        for expected in 1..=4 {
+            assert_eq!(pop_byte(&mut queue), Some(expected));
+        }
+    }
+
+    #[test]
+    fn service_mode_options() {
+        let mut queue = PacketQueue::<2>::new();
+        queue
+            .push([1].view_bits(), TxOptions::service_mode(2))
+            .unwrap();
+        let mut out = BufferType::default();
+        let (_, options) = queue.pop(&mut out).unwrap();
+        assert!(options.service_mode);
+        assert_eq!(options.priority, Priority::Normal);
+    }
+
+    #[test]
+    fn service_mode_sequences() {
+        let mut queue = PacketQueue::<4>::new();
+        queue
+            .push([1].view_bits(), TxOptions::service_mode(2))
+            .unwrap();
+        queue
+            .push([2].view_bits(), TxOptions::service_mode(2))
+            .unwrap();
+        queue
+            .push([3].view_bits(), TxOptions::service_mode(2))
+            .unwrap();
+
+        // repeats are sent back-to-back and not interrupted by a higher
+        // priority packet once the sequence has started
+        assert_eq!(pop_byte(&mut queue), Some(1));
+        queue
+            .push([4].view_bits(), TxOptions::new(Priority::Emergency, 1))
+            .unwrap();
+        for expected in [1, 2, 2, 3, 3, 4] {
+            assert_eq!(pop_byte(&mut queue), Some(expected));
+        }
+        assert_eq!(pop_byte(&mut queue), None);
+    }
+
+    #[test]
+    fn reject_long_packets() {
+        let mut queue = PacketQueue::<1>::new();
+        assert_eq!(
+            queue.push([0; 25].view_bits(), TxOptions::default()),
+            Err(Error::TooLong)
+        );
+    }
+}
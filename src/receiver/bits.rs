@@ -0,0 +1,165 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Bit-level decoder which turns the timings of the edges in a DCC signal
+//! into a stream of bits.
+//!
+//! Each half-bit is classified using the decoder tolerances from S-9.1: a
+//! half lasting between 52 and 64us is half of a "1", and a half lasting
+//! between 90 and 10000us is half of a "0". A bit is only produced once
+//! both of its halves have been received and agree.
+
+use core::ops::RangeInclusive;
+
+/// Permitted duration of each half of a "1" bit, in microseconds
+const ONE_MICROS: RangeInclusive<u32> = 52..=64;
+/// Permitted duration of each half of a "0" bit, in microseconds
+const ZERO_MICROS: RangeInclusive<u32> = 90..=10000;
+
+/// Counters of the malformed bits seen by a `BitDecoder`
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct BitErrors {
+    /// Half-bits whose duration was outside both tolerance windows
+    pub invalid_half_bits: u32,
+    /// Bits whose two halves did not agree
+    pub mismatched_bits: u32,
+}
+
+/// Decoder which classifies half-bit durations, e.g. measured by an input
+/// capture interrupt, and pairs them up into bits
+#[derive(Debug, Default)]
+pub struct BitDecoder {
+    last_edge: Option<u32>,
+    first_half: Option<bool>,
+    errors: BitErrors,
+}
+
+impl BitDecoder {
+    /// Create a decoder which has not yet seen any edges
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Process an edge at `timestamp` microseconds, e.g. the value of a
+    /// free-running timer captured on each edge. The timestamp may wrap
+    /// around. Returns the next bit if this edge completed one.
+    pub fn push_edge(&mut self, timestamp: u32) -> Option<bool> {
+        let last_edge = self.last_edge.replace(timestamp)?;
+        self.push_half_bit(timestamp.wrapping_sub(last_edge))
+    }
+
+    /// Process a half-bit lasting `micros` microseconds. Returns the next
+    /// bit if this half-bit completed one.
+    ///
+    /// If the two halves of a bit disagree then the decoder has most
+    /// likely lost track of which half is which, so the second half is
+    /// kept as the first half of the next bit.
+    pub fn push_half_bit(&mut self, micros: u32) -> Option<bool> {
+        let half = if ONE_MICROS.contains(&micros) {
+            true
+        } else if ZERO_MICROS.contains(&micros) {
+            false
+        } else {
+            self.errors.invalid_half_bits =
+                self.errors.invalid_half_bits.saturating_add(1);
+            self.first_half = None;
+            return None;
+        };
+
+        match self.first_half.take() {
+            None => {
+                self.first_half = Some(half);
+                None
+            }
+            Some(first) if first == half => Some(half),
+            Some(_) => {
+                self.errors.mismatched_bits =
+                    self.errors.mismatched_bits.saturating_add(1);
+                self.first_half = Some(half);
+                None
+            }
+        }
+    }
+
+    /// Forget any partially received bit, e.g. after a RailCom cutout or
+    /// once a packet has been received. Resynchronising between packets
+    /// ensures that the first half of the preamble is paired correctly.
+    pub fn reset(&mut self) {
+        self.last_edge = None;
+        self.first_half = None;
+    }
+
+    /// Counters of the malformed bits seen so far
+    pub fn errors(&self) -> BitErrors {
+        self.errors
+    }
+
+    /// Reset the error counters to zero
+    pub fn clear_errors(&mut self) {
+        self.errors = BitErrors::default();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_half_bits() {
+        let mut decoder = BitDecoder::new();
+        assert_eq!(decoder.push_half_bit(58), None);
+        assert_eq!(decoder.push_half_bit(52), Some(true));
+        assert_eq!(decoder.push_half_bit(100), None);
+        assert_eq!(decoder.push_half_bit(9000), Some(false));
+        assert_eq!(decoder.push_half_bit(64), None);
+        assert_eq!(decoder.push_half_bit(64), Some(true));
+        assert_eq!(decoder.errors(), BitErrors::default());
+    }
+
+    #[test]
+    fn decode_edges() {
+        let mut decoder = BitDecoder::new();
+        let mut timestamp = u32::MAX - 100;
+        assert_eq!(decoder.push_edge(timestamp), None);
+        let mut bits = [None; 4];
+        for (bit, micros) in bits.iter_mut().zip([58, 58, 100, 100]) {
+            timestamp = timestamp.wrapping_add(micros);
+            *bit = decoder.push_edge(timestamp);
+        }
+        assert_eq!(bits, [None, Some(true), None, Some(false)]);
+    }
+
+    #[test]
+    fn count_errors() {
+        let mut decoder = BitDecoder::new();
+
+        // out of both windows
+        for micros in [51, 70, 10001] {
+            assert_eq!(decoder.push_half_bit(micros), None);
+        }
+        assert_eq!(decoder.errors().invalid_half_bits, 3);
+
+        // an invalid half-bit discards the partially received bit
+        assert_eq!(decoder.push_half_bit(58), None);
+        assert_eq!(decoder.push_half_bit(5), None);
+        assert_eq!(decoder.push_half_bit(58), None);
+        assert_eq!(decoder.push_half_bit(58), Some(true));
+
+        // halves which disagree resynchronise on the second half
+        assert_eq!(decoder.push_half_bit(58), None);
+        assert_eq!(decoder.push_half_bit(100), None);
+        assert_eq!(decoder.push_half_bit(100), Some(false));
+        assert_eq!(
+            decoder.errors(),
+            BitErrors {
+                invalid_half_bits: 4,
+                mismatched_bits: 1,
+            }
+        );
+
+        decoder.clear_errors();
+        assert_eq!(decoder.errors(), BitErrors::default());
+    }
+}
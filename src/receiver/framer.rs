@@ -0,0 +1,338 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Packet framer which assembles the bits produced by a `BitDecoder` into
+//! packets. This is the inverse of `packets::serialise`.
+//!
+//! A packet starts after a preamble of at least 10 "1" bits, as required
+//! by S-9.2 for decoders. Each data byte is preceded by a "0" start bit and
+//! the packet ends with a "1" end bit, after which the error detection
+//! byte is checked. The end bit may also be the first bit of the next
+//! packet's preamble.
+
+use crate::packets::{RawPacket, MAX_BYTES};
+
+/// Minimum number of preamble bits which a decoder accepts
+const MIN_PREAMBLE: usize = 10;
+/// Minimum number of bytes in a packet: an address, an instruction and
+/// the error detection byte
+const MIN_BYTES: usize = 3;
+
+/// Counters of the malformed packets seen by a `Framer`
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct FrameErrors {
+    /// Packets whose error detection byte did not match
+    pub checksum_errors: u32,
+    /// Packets which were too short or too long
+    pub length_errors: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum State {
+    /// Counting the "1" bits of the preamble
+    Preamble { ones: usize },
+    /// Receiving the bits of a data byte
+    Byte { bits: usize },
+    /// Waiting for the start bit of the next byte or the end bit
+    StartOrEnd,
+}
+
+/// Framer which turns a stream of bits into packets
+#[derive(Debug)]
+pub struct Framer {
+    state: State,
+    bytes: [u8; MAX_BYTES],
+    len: usize,
+    errors: FrameErrors,
+}
+
+impl Default for Framer {
+    fn default() -> Self {
+        Self {
+            state: State::Preamble { ones: 0 },
+            bytes: [0; MAX_BYTES],
+            len: 0,
+            errors: FrameErrors::default(),
+        }
+    }
+}
+
+impl Framer {
+    /// Create a framer which is waiting for a preamble
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Process the next received bit. Returns the packet if this bit was
+    /// the end bit of a packet with a valid error detection byte.
+    pub fn push_bit(&mut self, bit: bool) -> Option<RawPacket> {
+        let (state, packet) = match (self.state, bit) {
+            (State::Preamble { ones }, true) => {
+                (State::Preamble { ones: ones + 1 }, None)
+            }
+            (State::Preamble { ones }, false) if ones >= MIN_PREAMBLE => {
+                self.len = 0;
+                (State::Byte { bits: 0 }, None)
+            }
+            (State::Preamble { .. }, false) => {
+                (State::Preamble { ones: 0 }, None)
+            }
+            (State::Byte { bits }, _) => {
+                let byte = &mut self.bytes[self.len];
+                *byte = (*byte << 1) | bit as u8;
+                if bits == 7 {
+                    self.len += 1;
+                    (State::StartOrEnd, None)
+                } else {
+                    (State::Byte { bits: bits + 1 }, None)
+                }
+            }
+            (State::StartOrEnd, false) if self.len < MAX_BYTES => {
+                (State::Byte { bits: 0 }, None)
+            }
+            (State::StartOrEnd, false) => {
+                self.errors.length_errors =
+                    self.errors.length_errors.saturating_add(1);
+                (State::Preamble { ones: 0 }, None)
+            }
+            (State::StartOrEnd, true) => {
+                // the end bit counts towards the next preamble
+                (State::Preamble { ones: 1 }, self.check_frame())
+            }
+        };
+        self.state = state;
+        packet
+    }
+
+    /// Validate the length and error detection byte of the received packet
+    fn check_frame(&mut self) -> Option<RawPacket> {
+        let bytes = &self.bytes[..self.len];
+        if bytes.len() < MIN_BYTES {
+            self.errors.length_errors =
+                self.errors.length_errors.saturating_add(1);
+            return None;
+        }
+        if bytes.iter().fold(0, |ecc, byte| ecc ^ byte) != 0 {
+            self.errors.checksum_errors =
+                self.errors.checksum_errors.saturating_add(1);
+            return None;
+        }
+        RawPacket::new(bytes).ok()
+    }
+
+    /// Discard any partially received packet and wait for a new preamble,
+    /// e.g. after the `BitDecoder` reported a malformed bit
+    pub fn reset(&mut self) {
+        self.state = State::Preamble { ones: 0 };
+    }
+
+    /// Counters of the malformed packets seen so far
+    pub fn errors(&self) -> FrameErrors {
+        self.errors
+    }
+
+    /// Reset the error counters to zero
+    pub fn clear_errors(&mut self) {
+        self.errors = FrameErrors::default();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packets::*;
+
+    /// Feed a serialised packet through a framer, check that it is the
+    /// inverse of `serialise` and return the received bytes
+    fn round_trip(buf: &SerialiseBuffer, len: usize) -> RawPacket {
+        let mut framer = Framer::new();
+        let mut frames = buf[..len]
+            .iter()
+            .by_vals()
+            .filter_map(|bit| framer.push_bit(bit));
+        let frame = frames.next().expect("packet was not received");
+        assert!(frames.next().is_none());
+        assert_eq!(framer.errors(), FrameErrors::default());
+
+        let mut reserialised = SerialiseBuffer::default();
+        let reserialised_len =
+            serialise(frame.bytes(), &mut reserialised).unwrap();
+        assert_eq!(reserialised_len, len);
+        assert_eq!(reserialised[..len], buf[..len]);
+        frame
+    }
+
+    macro_rules! assert_round_trip {
+        ($packet:expr) => {{
+            let mut buf = SerialiseBuffer::default();
+            let len = $packet.serialise(&mut buf).unwrap();
+            round_trip(&buf, len)
+        }};
+    }
+
+    #[test]
+    fn round_trip_baseline_packets() {
+        let frame = assert_round_trip!(Idle);
+        assert_eq!(frame.bytes(), [0xff, 0x00, 0xff]);
+        assert_eq!(frame.data(), [0xff, 0x00]);
+        assert_round_trip!(Reset);
+        assert_round_trip!(BroadcastStop::immediate());
+        assert_round_trip!(BroadcastStop::float());
+        assert_round_trip!(SpeedAndDirection::builder()
+            .address(10)
+            .unwrap()
+            .speed(14)
+            .unwrap()
+            .build());
+        assert_round_trip!(SpeedAndDirection::builder()
+            .long_address(1234)
+            .unwrap()
+            .build());
+    }
+
+    #[test]
+    fn round_trip_extended_packets() {
+        assert_round_trip!(SpeedAndDirection128::builder()
+            .long_address(10239)
+            .unwrap()
+            .speed(126)
+            .unwrap()
+            .build());
+        let mut functions = Functions::new();
+        functions.set(0, true).unwrap().set(68, true).unwrap();
+        for group in FunctionGroup::ALL {
+            assert_round_trip!(FunctionControl::builder()
+                .address(3)
+                .unwrap()
+                .group(group)
+                .functions(functions)
+                .build()
+                .unwrap());
+        }
+        let frame = assert_round_trip!(ProgrammingOnMain::builder()
+            .long_address(1234)
+            .unwrap()
+            .cv_address(1024)
+            .unwrap()
+            .write_byte(0xa5)
+            .build()
+            .unwrap());
+        assert_eq!(frame.bytes().len(), MAX_BYTES);
+        assert_round_trip!(BasicAccessory::builder()
+            .broadcast(3)
+            .unwrap()
+            .build()
+            .unwrap());
+        assert_round_trip!(ExtendedAccessory::builder()
+            .broadcast()
+            .aspect(31)
+            .build()
+            .unwrap());
+        assert_round_trip!(AccessoryProgrammingOnMain::builder()
+            .extended_address(2000)
+            .unwrap()
+            .cv_address(29)
+            .unwrap()
+            .verify_bit(3, true)
+            .unwrap()
+            .build()
+            .unwrap());
+        assert_round_trip!(DecoderControl::new(
+            Address::Long(100),
+            DecoderControlInstruction::SetAdvancedAddressing(true),
+        ));
+        assert_round_trip!(ConsistControl::set(
+            Address::Short(3),
+            127,
+            ConsistDirection::Reversed,
+        )
+        .unwrap());
+        assert_round_trip!(ConsistControl::clear(Address::Long(3)));
+    }
+
+    #[test]
+    fn round_trip_service_mode_packets() {
+        assert_round_trip!(PagePreset);
+        assert_round_trip!(FactoryReset);
+        assert_round_trip!(AddressQuery::address(3));
+        assert_round_trip!(AddressOnly::write(3).unwrap());
+        assert_round_trip!(AddressOnly::verify(3).unwrap());
+        assert_round_trip!(Instruction::builder()
+            .cv_address(1023)
+            .unwrap()
+            .write_bit(7, true)
+            .unwrap()
+            .build()
+            .unwrap());
+        assert_round_trip!(PhysicalRegister::builder()
+            .operation(Operation::Write)
+            .register(PhysicalRegister::ACCELERATION)
+            .unwrap()
+            .value(3)
+            .build()
+            .unwrap());
+        assert_round_trip!(DecoderLock::builder()
+            .address(3)
+            .unwrap()
+            .build()
+            .unwrap());
+    }
+
+    #[test]
+    fn back_to_back_packets() {
+        // the end bit of each packet is part of the next preamble, so a
+        // 9 bit preamble is enough after the first packet
+        let mut framer = Framer::new();
+        let mut buf = SerialiseBuffer::default();
+        let len = Idle.serialise(&mut buf).unwrap();
+        let mut frames = 0;
+        for bit in buf[..len].iter().by_vals() {
+            frames += framer.push_bit(bit).is_some() as usize;
+        }
+        for bit in buf[6..len].iter().by_vals() {
+            frames += framer.push_bit(bit).is_some() as usize;
+        }
+        assert_eq!(frames, 2);
+    }
+
+    #[test]
+    fn count_errors() {
+        let mut framer = Framer::new();
+        let mut buf = SerialiseBuffer::default();
+
+        // bad error detection byte
+        let len = serialise(&[0x03, 0x3f, 0x00], &mut buf).unwrap();
+        for bit in buf[..len].iter().by_vals() {
+            assert_eq!(framer.push_bit(bit), None);
+        }
+        // too short
+        let len = serialise(&[0x00, 0x00], &mut buf).unwrap();
+        for bit in buf[..len].iter().by_vals() {
+            assert_eq!(framer.push_bit(bit), None);
+        }
+        // preamble too short
+        let len = serialise(&[0xff, 0x00, 0xff], &mut buf).unwrap();
+        framer.reset();
+        for bit in buf[6..len].iter().by_vals() {
+            assert_eq!(framer.push_bit(bit), None);
+        }
+        assert_eq!(
+            framer.errors(),
+            FrameErrors {
+                checksum_errors: 1,
+                length_errors: 1,
+            }
+        );
+
+        // too long
+        let len = serialise(&[0; MAX_BYTES], &mut buf).unwrap();
+        framer.clear_errors();
+        for bit in buf[..len - 1].iter().by_vals() {
+            assert_eq!(framer.push_bit(bit), None);
+        }
+        assert_eq!(framer.push_bit(false), None);
+        assert_eq!(framer.errors().length_errors, 1);
+    }
+}
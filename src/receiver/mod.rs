@@ -0,0 +1,11 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Modules for receiving DCC signals, e.g. in a decoder or a sniffer
+
+pub mod bits;
+pub mod framer;
+
+pub use bits::*;
+pub use framer::*;
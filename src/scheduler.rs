@@ -0,0 +1,314 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Refresh scheduler which keeps track of the state of each active loco
+//! and continuously cycles through the packets needed to keep the
+//! decoders up to date.
+//!
+//! Decoders expect to be sent their state periodically, e.g. so that a
+//! loco which briefly lost contact with the track (or which was only just
+//! placed on it) picks up its speed and functions again.
+
+use crate::packets::{
+    Address, DccPacket, Direction, FunctionControl, FunctionGroup, Functions,
+    SerialiseBuffer, SpeedAndDirection128,
+};
+use crate::Error;
+
+/// Number of different packets sent to refresh a single loco: one speed
+/// packet followed by one packet per function group
+const PHASES: usize = 1 + FunctionGroup::ALL.len();
+
+/// The state of a single loco, as refreshed by the `Scheduler`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct LocoState {
+    /// Address of the loco's decoder
+    pub address: Address,
+    /// Speed, between 0 and 126. This is sent using the 128 speed step
+    /// packet.
+    pub speed: u8,
+    /// Direction of travel
+    pub direction: Direction,
+    /// Function states
+    pub functions: Functions,
+}
+
+impl LocoState {
+    /// Create the state of a stationary loco with all functions switched
+    /// off
+    pub fn new(address: Address) -> Self {
+        Self {
+            address,
+            speed: 0,
+            direction: Direction::default(),
+            functions: Functions::default(),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    state: LocoState,
+    phase: usize,
+    /// Bitmask of the function groups which have had at least one function
+    /// switched on since the loco was added
+    used_groups: u16,
+}
+
+impl Entry {
+    fn new(state: LocoState) -> Self {
+        let mut entry = Self {
+            state,
+            phase: 0,
+            used_groups: 0,
+        };
+        entry.update(state);
+        entry
+    }
+
+    fn update(&mut self, state: LocoState) {
+        self.state = state;
+        for (idx, &group) in FunctionGroup::ALL.iter().enumerate() {
+            if state.functions.any(group) {
+                self.used_groups |= 1 << idx;
+            }
+        }
+    }
+
+    /// Serialise the packet for the current phase into `buf` and advance
+    /// to the next phase. Functions F0-F12 are always refreshed, but the
+    /// feature expansion groups are skipped until one of their functions
+    /// is switched on. From then on the group keeps being refreshed, so
+    /// that decoders also see the function being switched off again.
+    fn next_packet(&mut self, buf: &mut SerialiseBuffer) -> Option<usize> {
+        let phase = self.phase;
+        self.phase = (phase + 1..PHASES)
+            .find(|&next| {
+                next <= 3 || self.used_groups & (1 << (next - 1)) != 0
+            })
+            .unwrap_or(0);
+
+        let address = self.state.address;
+        if phase == 0 {
+            let mut builder = SpeedAndDirection128::builder();
+            match address {
+                Address::Short(address) => builder.address(address),
+                Address::Long(address) => builder.long_address(address),
+            }
+            .ok()?
+            .speed(self.state.speed)
+            .ok()?
+            .direction(self.state.direction)
+            .build()
+            .serialise(buf)
+            .ok()
+        } else {
+            let mut builder = FunctionControl::builder();
+            match address {
+                Address::Short(address) => builder.address(address),
+                Address::Long(address) => builder.long_address(address),
+            }
+            .ok()?
+            .group(FunctionGroup::ALL[phase - 1])
+            .functions(self.state.functions)
+            .build()
+            .ok()?
+            .serialise(buf)
+            .ok()
+        }
+    }
+}
+
+/// Fixed-capacity table of up to `N` active locos. Each call to
+/// `next_packet` produces the next refresh packet, working round-robin
+/// through the locos so that consecutive packets are sent to different
+/// decoders wherever possible.
+pub struct Scheduler<const N: usize> {
+    entries: [Option<Entry>; N],
+    next: usize,
+}
+
+impl<const N: usize> Default for Scheduler<N> {
+    fn default() -> Self {
+        Self {
+            entries: [None; N],
+            next: 0,
+        }
+    }
+}
+
+impl<const N: usize> Scheduler<N> {
+    /// Create an empty scheduler
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a loco to the table, or update its state if it is already
+    /// present. Returns `Error::InvalidSpeed` if the speed is greater than
+    /// 126, or `Error::Full` if the table has no room for another loco.
+    pub fn update(&mut self, state: LocoState) -> Result<(), Error> {
+        if state.speed > 126 {
+            return Err(Error::InvalidSpeed);
+        }
+
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .flatten()
+            .find(|entry| entry.state.address == state.address)
+        {
+            entry.update(state);
+            return Ok(());
+        }
+
+        let slot = self
+            .entries
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(Error::Full)?;
+        *slot = Some(Entry::new(state));
+        Ok(())
+    }
+
+    /// Look up the state of the loco at `address`
+    pub fn get(&self, address: Address) -> Option<&LocoState> {
+        self.entries
+            .iter()
+            .flatten()
+            .map(|entry| &entry.state)
+            .find(|state| state.address == address)
+    }
+
+    /// Stop refreshing the loco at `address`, returning its last state
+    pub fn remove(&mut self, address: Address) -> Option<LocoState> {
+        let slot = self.entries.iter_mut().find(|slot| {
+            matches!(slot, Some(entry) if entry.state.address == address)
+        })?;
+        slot.take().map(|entry| entry.state)
+    }
+
+    /// Number of locos in the table
+    pub fn len(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    /// Returns `true` if there are no locos to refresh
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Serialise the next refresh packet into `buf`, returning the number
+    /// of bits written or `None` if there are no locos to refresh
+    pub fn next_packet(&mut self, buf: &mut SerialiseBuffer) -> Option<usize> {
+        for offset in 0..N {
+            let idx = (self.next + offset) % N;
+            if let Some(entry) = &mut self.entries[idx] {
+                self.next = (idx + 1) % N;
+                return entry.next_packet(buf);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packets::test::serialise_bytes;
+
+    #[test]
+    fn update_and_remove() {
+        let mut scheduler = Scheduler::<2>::new();
+        let mut state = LocoState::new(Address::Short(3));
+        scheduler.update(state).unwrap();
+        state.speed = 20;
+        scheduler.update(state).unwrap();
+        assert_eq!(scheduler.len(), 1);
+        assert_eq!(scheduler.get(Address::Short(3)).unwrap().speed, 20);
+
+        scheduler.update(LocoState::new(Address::Long(3))).unwrap();
+        assert_eq!(
+            scheduler.update(LocoState::new(Address::Short(4))),
+            Err(Error::Full)
+        );
+        state.speed = 127;
+        assert_eq!(scheduler.update(state), Err(Error::InvalidSpeed));
+
+        assert_eq!(scheduler.remove(Address::Short(3)).unwrap().speed, 20);
+        assert!(scheduler.remove(Address::Short(3)).is_none());
+        assert_eq!(scheduler.len(), 1);
+    }
+
+    #[test]
+    fn round_robin_refresh() {
+        let mut scheduler = Scheduler::<4>::new();
+        let mut buf = SerialiseBuffer::default();
+        assert!(scheduler.is_empty());
+        assert_eq!(scheduler.next_packet(&mut buf), None);
+
+        let mut state = LocoState::new(Address::Short(3));
+        state.speed = 1;
+        state.functions.set(0, true).unwrap();
+        scheduler.update(state).unwrap();
+        scheduler.update(LocoState::new(Address::Short(4))).unwrap();
+
+        // speed packets for each loco in turn, then their functions
+        let expected: [&[u8]; 6] = [
+            &[0x03, 0x3f, 0x82, 0x03 ^ 0x3f ^ 0x82],
+            &[0x04, 0x3f, 0x80, 0x04 ^ 0x3f ^ 0x80],
+            &[0x03, 0x90, 0x03 ^ 0x90],
+            &[0x04, 0x80, 0x04 ^ 0x80],
+            &[0x03, 0xb0, 0x03 ^ 0xb0],
+            &[0x04, 0xb0, 0x04 ^ 0xb0],
+        ];
+        for bytes in expected {
+            let len = scheduler.next_packet(&mut buf).unwrap();
+            let (expected, expected_len) = serialise_bytes(bytes);
+            assert_eq!(buf[..len], expected[..expected_len]);
+        }
+    }
+
+    #[test]
+    fn skip_inactive_expansion_groups() {
+        let mut scheduler = Scheduler::<1>::new();
+        let mut buf = SerialiseBuffer::default();
+        let mut state = LocoState::new(Address::Short(3));
+        state.functions.set(30, true).unwrap();
+        scheduler.update(state).unwrap();
+
+        // speed, three groups covering F0-F12, F29-F36 then speed again
+        for _ in 0..4 {
+            scheduler.next_packet(&mut buf).unwrap();
+        }
+        let len = scheduler.next_packet(&mut buf).unwrap();
+        let bytes = [0x03, 0xd8, 0x02, 0x03 ^ 0xd8 ^ 0x02];
+        let (expected, expected_len) = serialise_bytes(&bytes);
+        assert_eq!(buf[..len], expected[..expected_len]);
+        let len = scheduler.next_packet(&mut buf).unwrap();
+        let bytes = [0x03, 0x3f, 0x80, 0x03 ^ 0x3f ^ 0x80];
+        let (expected, expected_len) = serialise_bytes(&bytes);
+        assert_eq!(buf[..len], expected[..expected_len]);
+    }
+
+    #[test]
+    fn refresh_expansion_group_after_switching_off() {
+        let mut scheduler = Scheduler::<1>::new();
+        let mut buf = SerialiseBuffer::default();
+        let mut state = LocoState::new(Address::Short(3));
+        state.functions.set(20, true).unwrap();
+        scheduler.update(state).unwrap();
+        state.functions.set(20, false).unwrap();
+        scheduler.update(state).unwrap();
+
+        // speed, three groups covering F0-F12, then F13-F20 with F20 off
+        for _ in 0..4 {
+            scheduler.next_packet(&mut buf).unwrap();
+        }
+        let len = scheduler.next_packet(&mut buf).unwrap();
+        let bytes = [0x03, 0xde, 0x00, 0x03 ^ 0xde];
+        let (expected, expected_len) = serialise_bytes(&bytes);
+        assert_eq!(buf[..len], expected[..expected_len]);
+    }
+}
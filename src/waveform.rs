@@ -0,0 +1,248 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Alternative to calling `DccInterruptHandler::tick` on every bit-half:
+//! whole packets are rendered into a table of timer values which can be
+//! played out by a timer using DMA.
+//!
+//! The `WaveformGenerator` holds two tables. One is played out by the DMA
+//! controller while the next packet is rendered into the other, and the
+//! two are swapped when the DMA transfer completes.
+
+use crate::config::Config;
+use crate::packets::{DccPacket, Idle, SerialiseBuffer};
+use crate::Error;
+use bitvec::prelude::*;
+
+/// Layout of the rendered timer values
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum WaveformFormat {
+    /// One value per bit-half, holding its duration. Suitable for a timer
+    /// in toggle mode whose auto-reload register is updated by DMA.
+    HalfBits,
+    /// Two values per bit: the period of the whole bit followed by the
+    /// compare value at which the output changes, which is the duration of
+    /// the first half. Suitable for a timer in PWM mode whose auto-reload
+    /// and compare registers are updated by a DMA burst.
+    Pwm,
+}
+
+/// Renders packets into double-buffered tables of up to `N` timer values
+pub struct WaveformGenerator<const N: usize> {
+    config: Config,
+    ticks_per_micro: u32,
+    format: WaveformFormat,
+    buffers: [[u16; N]; 2],
+    lens: [usize; 2],
+    active: usize,
+    rendered: bool,
+}
+
+impl<const N: usize> WaveformGenerator<N> {
+    /// Create a generator for a timer which counts `ticks_per_micro` times
+    /// per microsecond. The bit timings and preamble lengths are taken
+    /// from `config`.
+    pub fn new(
+        config: Config,
+        ticks_per_micro: u32,
+        format: WaveformFormat,
+    ) -> Self {
+        Self {
+            config,
+            ticks_per_micro,
+            format,
+            buffers: [[0; N]; 2],
+            lens: [0; 2],
+            active: 0,
+            rendered: false,
+        }
+    }
+
+    /// Render a serialised packet into the inactive table, replacing the
+    /// previous contents. The preamble is lengthened or shortened to the
+    /// configured operations-mode or service-mode length.
+    ///
+    /// Returns `Error::TooLong` if the rendered packet does not fit into
+    /// the table, or `Error::InvalidTiming` if a timer value does not fit
+    /// into 16 bits.
+    pub fn render(
+        &mut self,
+        packet: &BitSlice<u8, Msb0>,
+        service_mode: bool,
+    ) -> Result<(), Error> {
+        let one = self.ticks(self.config.one_micros())?;
+        let zero = self.ticks(self.config.zero_micros())?;
+        let preamble = if service_mode {
+            self.config.service_mode_preamble()
+        } else {
+            self.config.preamble()
+        };
+
+        // skip or add preamble bits to reach the configured length
+        let current = packet.first_zero().unwrap_or(packet.len());
+        let skip = current.saturating_sub(preamble);
+        let extra = preamble.saturating_sub(current);
+        let bits = core::iter::repeat_n(true, extra)
+            .chain(packet[skip..].iter().by_vals());
+
+        // both formats use two values per bit
+        if (packet.len() - skip + extra) * 2 > N {
+            return Err(Error::TooLong);
+        }
+
+        let inactive = 1 - self.active;
+        let buffer = &mut self.buffers[inactive];
+        let mut len = 0;
+        for bit in bits {
+            let half = if bit { one } else { zero };
+            let values = match self.format {
+                WaveformFormat::HalfBits => [half, half],
+                WaveformFormat::Pwm => [half * 2, half],
+            };
+            buffer[len..len + 2].copy_from_slice(&values);
+            len += 2;
+        }
+        self.lens[inactive] = len;
+        self.rendered = true;
+        Ok(())
+    }
+
+    /// Swap the tables once the active one has finished playing, returning
+    /// the newly rendered table to be passed to the DMA controller. If no
+    /// packet has been rendered since the last swap then an `Idle` packet
+    /// is rendered instead, so that the track signal continues without
+    /// replaying an old packet.
+    pub fn swap(&mut self) -> &[u16] {
+        if !self.rendered {
+            self.render_idle();
+        }
+        self.rendered = false;
+        self.active = 1 - self.active;
+        self.active()
+    }
+
+    /// The table which is currently being played out
+    pub fn active(&self) -> &[u16] {
+        &self.buffers[self.active][..self.lens[self.active]]
+    }
+
+    /// Render an `Idle` packet into the inactive table. If that fails then
+    /// no packet fits the table, so it is left empty.
+    fn render_idle(&mut self) {
+        let mut buf = SerialiseBuffer::default();
+        let rendered = Idle
+            .serialise(&mut buf)
+            .and_then(|len| self.render(&buf[..len], false));
+        if rendered.is_err() {
+            self.lens[1 - self.active] = 0;
+        }
+    }
+
+    /// Convert a duration in microseconds into timer ticks. PWM periods
+    /// are twice as long as a bit-half, so must also fit into 16 bits.
+    fn ticks(&self, micros: u32) -> Result<u16, Error> {
+        let ticks = micros
+            .checked_mul(self.ticks_per_micro)
+            .ok_or(Error::InvalidTiming)?;
+        let max = match self.format {
+            WaveformFormat::HalfBits => u16::MAX as u32,
+            WaveformFormat::Pwm => u16::MAX as u32 / 2,
+        };
+        if ticks > max {
+            return Err(Error::InvalidTiming);
+        }
+        Ok(ticks as u16)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packets::Reset;
+
+    #[test]
+    fn render_half_bits() {
+        let mut generator = WaveformGenerator::<200>::new(
+            Config::default(),
+            1,
+            WaveformFormat::HalfBits,
+        );
+        let mut buf = SerialiseBuffer::default();
+        let len = Idle.serialise(&mut buf).unwrap();
+        generator.render(&buf[..len], false).unwrap();
+        assert!(generator.active().is_empty());
+
+        let table = generator.swap();
+        assert_eq!(table.len(), len * 2);
+        assert!(table[..30].iter().all(|&ticks| ticks == 58));
+        assert_eq!(table[30..32], [100, 100]);
+        assert_eq!(table[len * 2 - 2..], [58, 58]);
+    }
+
+    #[test]
+    fn swap_without_render_sends_idle() {
+        let mut generator = WaveformGenerator::<200>::new(
+            Config::default(),
+            1,
+            WaveformFormat::HalfBits,
+        );
+        let mut buf = SerialiseBuffer::default();
+        let len = Reset.serialise(&mut buf).unwrap();
+        generator.render(&buf[..len], false).unwrap();
+        // the first address byte is all zeros
+        assert_eq!(generator.swap()[32..34], [100, 100]);
+
+        // the reset packet is not replayed if nothing new has been rendered
+        for _ in 0..2 {
+            let table = generator.swap();
+            assert_eq!(table.len(), len * 2);
+            assert_eq!(table[32..34], [58, 58]);
+        }
+    }
+
+    #[test]
+    fn render_pwm_with_service_mode_preamble() {
+        let mut generator = WaveformGenerator::<200>::new(
+            Config::default(),
+            8,
+            WaveformFormat::Pwm,
+        );
+        let mut buf = SerialiseBuffer::default();
+        let len = Idle.serialise(&mut buf).unwrap();
+        generator.render(&buf[..len], true).unwrap();
+
+        let table = generator.swap();
+        assert_eq!(table.len(), (len + 5) * 2);
+        assert!(table[..40].chunks(2).all(|bit| bit == [928, 464]));
+        assert_eq!(table[40..42], [1600, 800]);
+
+        // rendering into the inactive table does not disturb the active one
+        generator.render(&buf[..len], false).unwrap();
+        assert_eq!(generator.active().len(), (len + 5) * 2);
+        assert_eq!(generator.swap().len(), len * 2);
+    }
+
+    #[test]
+    fn render_limits() {
+        let mut generator = WaveformGenerator::<20>::new(
+            Config::default(),
+            1,
+            WaveformFormat::HalfBits,
+        );
+        let mut buf = SerialiseBuffer::default();
+        let len = Idle.serialise(&mut buf).unwrap();
+        assert_eq!(generator.render(&buf[..len], false), Err(Error::TooLong));
+
+        let mut generator = WaveformGenerator::<200>::new(
+            Config::default(),
+            1000,
+            WaveformFormat::Pwm,
+        );
+        assert_eq!(
+            generator.render(&buf[..len], false),
+            Err(Error::InvalidTiming)
+        );
+    }
+}